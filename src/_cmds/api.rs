@@ -1,4 +1,5 @@
 use std::{
+    cell::RefCell,
     collections::{BTreeMap, BTreeSet, HashMap},
     net::SocketAddr,
     sync::Arc,
@@ -12,22 +13,47 @@ use tokio::net::TcpListener;
 
 use backshots::{
     data::{
-        did::resolve_did,
-        record::{resolve_collection, resolve_rkey, RecordId},
+        cid::CidV1Sha256,
+        did::{encode_existing_did, is_did, resolve_did},
+        record::{find_link_paths, resolve_collection, resolve_rkey, RecordId},
     },
+    db::setup_db,
     get_app_config,
     http::{body_full, Body},
-    storage::{compacted::CompactedStorageReader, live_guards::LiveReadHandle},
+    storage::catalog::StoreCatalog,
     AppConfig, AppContext,
 };
 
-fn get_response(cfg: Arc<AppConfig>, req: Request<Incoming>) -> Result<Response<Body>> {
-    let mut app = AppContext::new(&cfg)?;
+pub struct ApiState {
+    cfg: AppConfig,
+    catalog: StoreCatalog,
+}
+
+thread_local! {
+    // requests run on tokio's blocking pool, so each of its threads keeps a context around
+    static APP: RefCell<Option<AppContext>> = const { RefCell::new(None) };
+}
+
+fn get_response(state: Arc<ApiState>, req: Request<Incoming>) -> Result<Response<Body>> {
+    APP.with_borrow_mut(|app| {
+        let app = match app {
+            Some(app) => app,
+            None => app.insert(AppContext::new(&state.cfg)?),
+        };
+        respond(&state, app, req)
+    })
+}
+
+fn respond(
+    state: &ApiState,
+    app: &mut AppContext,
+    req: Request<Incoming>,
+) -> Result<Response<Body>> {
     let path = req.uri().path();
 
     match (req.method(), path) {
         (&Method::GET, "/") => {
-            let db = app.db;
+            let db = &app.db;
 
             let collection_count: u64 =
                 db.query_row("SELECT COUNT(id) FROM collections", (), |row| row.get(0))?;
@@ -52,7 +78,12 @@ backlinks: {}
 outline rkeys: {}
 non-zplc dids: {}
 
-the backlink listing API endpoint is located at:  GET /links?uri=<at-uri>"#,
+the backlink listing API endpoint is located at:  GET /links?uri=<at-uri>
+(add &path=<path> to only list backlinks from there in the source record, e.g. &path=reply.parent)
+(add &cid=<cid> to leave out backlinks to other versions of the record, where we know which one they point at)
+
+records linking to an identity itself (follows, blocks, list memberships...):  GET /links/identity?did=<did>
+(&path=<path> works here too, e.g. &path=subject)"#,
                     collection_count, backlink_count, rkey_count, did_count,
                 )))?)
         }
@@ -70,82 +101,75 @@ the backlink listing API endpoint is located at:  GET /links?uri=<at-uri>"#,
                     .body(body_full("'uri' param missing"))?);
             };
 
-            let Ok(record_id) = RecordId::from_at_uri(&mut app, at_uri) else {
+            let Ok(record_id) = RecordId::from_at_uri(app, at_uri) else {
                 return Ok(Response::builder()
                     .status(StatusCode::BAD_REQUEST)
                     .body(body_full("'uri' param was not a valid at-uri"))?);
             };
 
-            let mut sources = BTreeSet::<RecordId>::new();
-            {
-                // TODO: we should be able to persist the open stores per-thread
-                // and then only refresh them periodically instead of opening them on every request
-
-                let mut statement = app
-                    .db
-                    .prepare("SELECT id, name, type FROM data_stores ORDER BY id ASC")?;
-                let stores = statement.query_map((), |row| {
-                    let id: u64 = row.get(0)?;
-                    let name: String = row.get(1)?;
-                    let type_ = row.get_ref(2)?;
-                    let is_live = type_.as_str()?.eq("live");
-                    Ok((id, name, is_live))
-                })?;
-                for store in stores {
-                    let (_store_id, name, is_live) = store?;
-                    if is_live {
-                        let mut storage = LiveReadHandle::new(&app, name)?;
-                        storage.read_backlinks(&record_id, &mut sources)?;
-                    } else {
-                        let mut storage =
-                            CompactedStorageReader::new(app.data_dir.join("compacted").join(name))?;
-                        storage.read_backlinks(&record_id, &mut sources)?;
-                    }
+            let cid = match q.get("cid").map(|cid| cid.parse::<CidV1Sha256>()) {
+                Some(Ok(cid)) => Some(cid.truncated_hash()),
+                Some(Err(_)) => {
+                    return Ok(Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .body(body_full("'cid' param was not a valid cid"))?)
                 }
-            }
+                None => None,
+            };
 
-            let mut backlink_uris = BTreeMap::<String, BTreeSet<String>>::new();
-            for source in sources {
-                let did = resolve_did(&app, source.did)?;
-                let collection = resolve_collection(&app, source.collection)?;
-                let rkey = resolve_rkey(&app, source.rkey)?;
-                let links = backlink_uris.entry(collection.clone()).or_default();
-                links.insert(format!("at://{did}/{collection}/{rkey}"));
+            let mut sources = BTreeSet::<RecordId>::new();
+            let snapshot = state.catalog.snapshot()?;
+            if let Some(cid) = cid {
+                let mut cids = BTreeMap::new();
+                snapshot.read_backlinks_with_cids(&record_id, &mut sources, &mut cids)?;
+                sources.retain(|source| cids.get(source).is_none_or(|c| *c == cid));
+            } else {
+                snapshot.read_backlinks(&record_id, &mut sources)?;
+            }
+            if let Some(path) = q.get("path") {
+                let paths = find_link_paths(app, path)?;
+                sources.retain(|source| paths.contains(&source.path()));
             }
 
-            // manually stringify the json just because we know the exact shape,
-            // + putting the data into tinyjson means converting BTreeMaps
-            // into HashMaps and BTreeSets into Vecs :/
-            let json = {
-                let mut json = String::new();
-                json.push('{');
-
-                let mut first = true;
-                for (collection, links) in backlink_uris {
-                    if !first {
-                        json.push(',');
-                    }
-                    first = false;
-
-                    json.push_str(&tinyjson::JsonValue::String(collection).stringify()?);
-                    json.push(':');
-
-                    json.push('[');
-                    {
-                        let mut first = true;
-                        for link in links {
-                            if !first {
-                                json.push(',');
-                            }
-                            first = false;
-                            json.push_str(&tinyjson::JsonValue::String(link).stringify()?);
-                        }
-                    }
-                    json.push(']');
-                }
-                json.push('}');
-                json
+            let json = sources_json(app, sources)?;
+
+            Ok(Response::builder()
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(body_full(json))?)
+        }
+
+        (&Method::GET, "/links/identity") => {
+            let q: HashMap<_, _> = req
+                .uri()
+                .query()
+                .map(|v| form_urlencoded::parse(v.as_bytes()).collect())
+                .unwrap_or_default();
+
+            let Some(did) = q.get("did") else {
+                return Ok(Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(body_full("'did' param missing"))?);
             };
+            if !is_did(did) {
+                return Ok(Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(body_full("'did' param was not a valid did"))?);
+            }
+
+            // a did we've never seen can't have been linked to
+            let mut sources = BTreeSet::<RecordId>::new();
+            if let Some(did) = encode_existing_did(app, did)? {
+                state
+                    .catalog
+                    .snapshot()?
+                    .read_identity_links(did, &mut sources)?;
+            }
+            if let Some(path) = q.get("path") {
+                let paths = find_link_paths(app, path)?;
+                sources.retain(|source| paths.contains(&source.path()));
+            }
+
+            let json = sources_json(app, sources)?;
 
             Ok(Response::builder()
                 .header(header::CONTENT_TYPE, "application/json")
@@ -159,8 +183,52 @@ the backlink listing API endpoint is located at:  GET /links?uri=<at-uri>"#,
     }
 }
 
-async fn serve(cfg: Arc<AppConfig>, req: Request<Incoming>) -> Result<Response<Body>> {
-    match tokio::task::spawn_blocking(move || get_response(cfg, req)).await? {
+// lists the sources grouped by collection, as `{"<collection>": ["<at-uri>", ...], ...}`
+fn sources_json(app: &AppContext, sources: BTreeSet<RecordId>) -> Result<String> {
+    let mut backlink_uris = BTreeMap::<String, BTreeSet<String>>::new();
+    for source in sources {
+        let did = resolve_did(app, source.did)?;
+        let collection = resolve_collection(app, source.collection)?;
+        let rkey = resolve_rkey(app, source.rkey)?;
+        let links = backlink_uris.entry(collection.clone()).or_default();
+        links.insert(format!("at://{did}/{collection}/{rkey}"));
+    }
+
+    // manually stringify the json just because we know the exact shape,
+    // + putting the data into tinyjson means converting BTreeMaps
+    // into HashMaps and BTreeSets into Vecs :/
+    let mut json = String::new();
+    json.push('{');
+
+    let mut first = true;
+    for (collection, links) in backlink_uris {
+        if !first {
+            json.push(',');
+        }
+        first = false;
+
+        json.push_str(&tinyjson::JsonValue::String(collection).stringify()?);
+        json.push(':');
+
+        json.push('[');
+        {
+            let mut first = true;
+            for link in links {
+                if !first {
+                    json.push(',');
+                }
+                first = false;
+                json.push_str(&tinyjson::JsonValue::String(link).stringify()?);
+            }
+        }
+        json.push(']');
+    }
+    json.push('}');
+    Ok(json)
+}
+
+async fn serve(state: Arc<ApiState>, req: Request<Incoming>) -> Result<Response<Body>> {
+    match tokio::task::spawn_blocking(move || get_response(state, req)).await? {
         Ok(res) => Ok(res),
         Err(err) => {
             tracing::error!("error handling request: {err:?}");
@@ -172,20 +240,20 @@ async fn serve(cfg: Arc<AppConfig>, req: Request<Incoming>) -> Result<Response<B
     }
 }
 
-pub async fn listen(cfg: Arc<AppConfig>, addr: SocketAddr) -> Result<()> {
+pub async fn listen(state: Arc<ApiState>, addr: SocketAddr) -> Result<()> {
     let listener = TcpListener::bind(addr).await?;
 
     loop {
         let (stream, _client_addr) = listener.accept().await?;
         let io = TokioIo::new(stream);
 
-        let cfg = Arc::clone(&cfg);
+        let state = Arc::clone(&state);
 
         tokio::task::spawn(async move {
             if let Err(err) = http1::Builder::new()
                 .serve_connection(
                     io,
-                    hyper::service::service_fn(move |req| serve(Arc::clone(&cfg), req)),
+                    hyper::service::service_fn(move |req| serve(Arc::clone(&state), req)),
                 )
                 .with_upgrades()
                 .await
@@ -208,9 +276,13 @@ pub async fn main() -> anyhow::Result<()> {
         .as_deref()
         .unwrap_or("127.0.0.1:3000")
         .parse()?;
-    let cfg = Arc::new(get_app_config()?);
+    let cfg = get_app_config()?;
+    let catalog_db = rusqlite::Connection::open(cfg.data_dir.join("db"))?;
+    setup_db(&catalog_db)?;
+    let catalog = StoreCatalog::open(&cfg.data_dir, catalog_db)?;
+    let state = Arc::new(ApiState { cfg, catalog });
     println!("Listening at: http://{addr}/ ...");
-    listen(cfg, addr).await?;
+    listen(state, addr).await?;
 
     Ok(())
 }
@@ -1,6 +1,6 @@
 use std::{
-    collections::BTreeSet,
-    path::PathBuf,
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
@@ -8,23 +8,32 @@ use std::{
     time::Duration,
 };
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use backshots::{
     db::{setup_db, DbConnection},
     get_app_config,
-    storage::{compacted::CompactedStorageWriter, live::LiveStorageReader},
+    storage::{
+        compacted::CompactedStorageWriter,
+        compaction::{
+            find_live_store_to_compact, finish_compaction, mark_compaction_in_progress,
+            max_compacted_links_size, prepare_compaction, recover_abandoned_compactions,
+            CompactionLock,
+        },
+        live::{LiveStorageReader, SUBSTORE_DIRS},
+        live_guards::StoreLease,
+        merge::{claim_stores, count_merge_input_entries, find_merge_candidates, merge_stores},
+    },
     AppConfig,
 };
 use indicatif::{MultiProgress, ProgressBar};
-use nix::{libc::pid_t, sys::signal::kill};
 use tokio::task::JoinHandle;
 
-fn get_candidate_live_store(cfg: &AppConfig, db: &DbConnection) -> Result<(String, PathBuf)> {
-    let store: String = db.query_row(
-        "SELECT name FROM data_stores WHERE type = 'live' AND compaction_in_progress = 0 ORDER BY id ASC LIMIT 1",
-        (),
-        |row| row.get(0),
-    )?;
+fn get_candidate_live_store(
+    cfg: &AppConfig,
+    db: &DbConnection,
+) -> Result<(String, PathBuf, CompactionLock, StoreLease)> {
+    let (store, lock) = find_live_store_to_compact(&cfg.data_dir, db)?
+        .context("no live store is waiting to be compacted")?;
 
     let store_dir = cfg.data_dir.join("live").join(&store);
 
@@ -45,57 +54,92 @@ fn get_candidate_live_store(cfg: &AppConfig, db: &DbConnection) -> Result<(Strin
         anyhow::bail!("not big enough yet ({total_size} bytes)");
     }
 
-    for file in std::fs::read_dir(&store_dir)?.filter_map(Result::ok) {
-        let Ok(file_name) = file.file_name().into_string() else {
-            continue;
-        };
-        let Some(pid) = file_name.strip_suffix(".pid") else {
-            continue;
-        };
-        let pid: pid_t = pid.parse()?;
-        let pid = nix::unistd::Pid::from_raw(pid);
-        let proc_running = !matches!(kill(pid, None), Err(nix::errno::Errno::ESRCH));
-        if proc_running {
-            anyhow::bail!("process with pid {pid} is still running!")
-        }
-    }
+    let lease = StoreLease::try_compact(&store_dir)?
+        .with_context(|| format!("{store} is still being written to"))?;
 
-    db.execute(
-        "UPDATE data_stores SET compaction_in_progress = 1 WHERE name = ?",
-        [&store],
-    )?;
+    mark_compaction_in_progress(db, &store)?;
 
-    Ok((store, store_dir))
+    Ok((store, store_dir, lock, lease))
 }
 
-fn compact_live_store(
-    mpb: MultiProgress,
-    cfg: &AppConfig,
-    store: String,
-    store_dir: PathBuf,
-) -> Result<()> {
-    let mut reader = LiveStorageReader::new(store_dir)?;
-    mpb.println(format!("reading index from {store}…"))?;
+fn compact(mpb: &MultiProgress, live_dir: &Path, compacted_dir: &Path, name: String) -> Result<()> {
+    let mut reader = LiveStorageReader::new(live_dir)?;
+    mpb.println(format!("reading index from {name}…"))?;
     let targets = reader.list_all_targets()?;
     mpb.println(format!("compacting {} targets…", targets.len()))?;
 
-    let compacted_store_dir = cfg.data_dir.join("compacted").join(&store);
-    let mut writer = CompactedStorageWriter::new(compacted_store_dir)?;
+    let mut writer =
+        CompactedStorageWriter::new_for_size(compacted_dir, max_compacted_links_size(live_dir))?;
 
-    let pb = mpb.add(ProgressBar::new(targets.len() as u64).with_message(store.clone()));
+    let pb = mpb.add(ProgressBar::new(targets.len() as u64).with_message(name));
     for (target, index_entry) in targets {
-        let mut sources = BTreeSet::new();
-        reader.read_backlinks_from_index_entry(&index_entry, &mut sources)?;
-        writer.log_backlinks(&target, &sources)?;
+        let (mut sources, mut cids) = (BTreeSet::new(), BTreeMap::new());
+        reader.read_backlinks_with_cids_from_index_entry(&index_entry, &mut sources, &mut cids)?;
+        if !sources.is_empty() {
+            writer.log_backlinks_with_cids(&target, &sources, &cids)?;
+        }
         pb.inc(1);
     }
+    writer.finish()?;
     pb.finish();
 
+    Ok(())
+}
+
+fn compact_live_store(
+    mpb: MultiProgress,
+    cfg: &AppConfig,
+    store: String,
+    store_dir: PathBuf,
+    lock: CompactionLock,
+    // keeps writers out until the compacted store has replaced it
+    _lease: StoreLease,
+) -> Result<()> {
     let db = rusqlite::Connection::open(cfg.data_dir.join("db"))?;
-    db.execute(
-        "UPDATE data_stores SET type = 'compacted' WHERE name = ?",
-        [store],
-    )?;
+    if let Some(tmp_dir) = prepare_compaction(&cfg.data_dir, &db, &store)? {
+        compact(&mpb, &store_dir, &tmp_dir, store.clone())?;
+        for sub_dir in SUBSTORE_DIRS {
+            if store_dir.join(sub_dir).exists() {
+                compact(
+                    &mpb,
+                    &store_dir.join(sub_dir),
+                    &tmp_dir.join(sub_dir),
+                    format!("{store}/{sub_dir}"),
+                )?;
+            }
+        }
+    }
+
+    finish_compaction(&cfg.data_dir, &db, &store, lock)?;
+
+    Ok(())
+}
+
+fn get_merge_candidates(
+    cfg: &AppConfig,
+    db: &mut DbConnection,
+) -> Result<(Vec<String>, Vec<CompactionLock>)> {
+    let names = find_merge_candidates(&cfg.data_dir, db)?.context("no size tier is full yet")?;
+    let locks = claim_stores(&cfg.data_dir, db, &names)?;
+    Ok((names, locks))
+}
+
+fn merge_compacted_stores(
+    mpb: MultiProgress,
+    cfg: &AppConfig,
+    names: Vec<String>,
+    locks: Vec<CompactionLock>,
+) -> Result<()> {
+    mpb.println(format!("merging {}…", names.join(", ")))?;
+    let mut db = rusqlite::Connection::open(cfg.data_dir.join("db"))?;
+
+    let pb = mpb.add(ProgressBar::new(count_merge_input_entries(
+        &cfg.data_dir,
+        &names,
+    )?));
+    let merged = merge_stores(&cfg.data_dir, &mut db, &names, locks, || pb.inc(1))?;
+    pb.finish();
+    mpb.println(format!("merged {} stores into {merged}", names.len()))?;
 
     Ok(())
 }
@@ -103,7 +147,7 @@ fn compact_live_store(
 #[tokio::main]
 async fn main() -> Result<()> {
     let cfg = Arc::new(get_app_config()?);
-    let db = rusqlite::Connection::open(cfg.data_dir.join("db"))?;
+    let mut db = rusqlite::Connection::open(cfg.data_dir.join("db"))?;
     setup_db(&db)?;
 
     let shutdown = Arc::new(AtomicBool::new(false));
@@ -118,21 +162,51 @@ async fn main() -> Result<()> {
     let mut tasks = Vec::<JoinHandle<_>>::new();
     let mpb = MultiProgress::new();
 
+    // pick up where any compactions that were running when we last died left off
+    for (store, lock) in recover_abandoned_compactions(&cfg.data_dir, &db)? {
+        let store_path = cfg.data_dir.join("live").join(&store);
+        let Some(lease) = StoreLease::try_compact(&store_path)? else {
+            mpb.println(format!(
+                "{store} is being written to again, not restarting its compaction"
+            ))?;
+            continue;
+        };
+        mpb.println(format!("restarting abandoned compaction of {store}…"))?;
+        let mpb = mpb.clone();
+        let cfg = Arc::clone(&cfg);
+        tasks.push(tokio::task::spawn_blocking(move || {
+            compact_live_store(mpb, &cfg, store, store_path, lock, lease)
+        }));
+    }
+
     while !shutdown.load(Ordering::Relaxed) {
-        let (store, store_path) = match get_candidate_live_store(&cfg, &db) {
-            Ok(details) => details,
-            Err(e) => {
-                mpb.println(format!("{e:?}"))?;
-                tokio::time::sleep(Duration::from_millis(1000)).await;
+        match get_candidate_live_store(&cfg, &db) {
+            Ok((store, store_path, lock, lease)) => {
+                let mpb = mpb.clone();
+                let cfg = Arc::clone(&cfg);
+                let join_handle = tokio::task::spawn_blocking(move || {
+                    compact_live_store(mpb, &cfg, store, store_path, lock, lease)
+                });
+                tasks.push(join_handle);
                 continue;
             }
-        };
+            Err(e) => mpb.println(format!("{e:?}"))?,
+        }
 
-        let mpb = mpb.clone();
-        let cfg = Arc::clone(&cfg);
-        let join_handle =
-            tokio::task::spawn_blocking(move || compact_live_store(mpb, &cfg, store, store_path));
-        tasks.push(join_handle);
+        match get_merge_candidates(&cfg, &mut db) {
+            Ok((names, locks)) => {
+                let mpb = mpb.clone();
+                let cfg = Arc::clone(&cfg);
+                let join_handle = tokio::task::spawn_blocking(move || {
+                    merge_compacted_stores(mpb, &cfg, names, locks)
+                });
+                tasks.push(join_handle);
+                continue;
+            }
+            Err(e) => mpb.println(format!("{e:?}"))?,
+        }
+
+        tokio::time::sleep(Duration::from_millis(1000)).await;
     }
 
     for task in tasks {
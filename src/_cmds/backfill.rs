@@ -32,6 +32,7 @@ async fn main() -> Result<()> {
 
     let cfg = get_app_config()?;
     let mut app = AppContext::new(&cfg)?;
+    app.load_lexicons(&cfg)?;
 
     let backfill_db = open_backfill_db(&cfg)?;
 
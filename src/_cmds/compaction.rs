@@ -1,57 +1,88 @@
-use std::collections::BTreeSet;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use backshots::{
     db::setup_db,
     get_app_config,
-    storage::{compacted::CompactedStorageWriter, live::LiveStorageReader},
+    storage::{
+        compacted::CompactedStorageWriter,
+        compaction::{
+            claim_live_store, find_live_store_to_compact, finish_compaction,
+            mark_compaction_in_progress, max_compacted_links_size, prepare_compaction,
+        },
+        live::{LiveStorageReader, SUBSTORE_DIRS},
+        live_guards::StoreLease,
+    },
 };
 use indicatif::ProgressBar;
 
-fn main() -> Result<()> {
-    let cfg = get_app_config()?;
-    let target = std::env::args()
-        .nth(1)
-        .expect("please provide name to compress"); // todo: probably should get some real option parsing
-
-    let target = if &target == "oldest" {
-        let db = rusqlite::Connection::open(cfg.data_dir.join("db"))?;
-        setup_db(&db)?;
-        let target = db.query_row(
-            "SELECT name FROM data_stores WHERE type = 'live' AND compaction_in_progress = 0 ORDER BY id ASC LIMIT 1",
-            (),
-            |row| row.get(0),
-        )?;
-        db.execute(
-            "UPDATE data_stores SET compaction_in_progress = 1 WHERE name = ?",
-            [&target],
-        )?;
-        target
-    } else {
-        target
-    };
-
-    let mut reader = LiveStorageReader::new(cfg.data_dir.join("live").join(&target))?;
-    println!("reading index from {target}…");
+fn compact(live_dir: &Path, compacted_dir: &Path) -> Result<()> {
+    let mut reader = LiveStorageReader::new(live_dir)?;
+    println!("reading index from {}…", live_dir.display());
     let targets = reader.list_all_targets()?;
     println!("compacting {} targets…", targets.len());
 
-    let mut writer = CompactedStorageWriter::new(cfg.data_dir.join("compacted").join(&target))?;
+    let mut writer =
+        CompactedStorageWriter::new_for_size(compacted_dir, max_compacted_links_size(live_dir))?;
 
     let pb = ProgressBar::new(targets.len() as u64);
     for (target, index_entry) in targets {
-        let mut sources = BTreeSet::new();
-        reader.read_backlinks_from_index_entry(&index_entry, &mut sources)?;
-        writer.log_backlinks(&target, &sources)?;
+        let (mut sources, mut cids) = (BTreeSet::new(), BTreeMap::new());
+        reader.read_backlinks_with_cids_from_index_entry(&index_entry, &mut sources, &mut cids)?;
+        if !sources.is_empty() {
+            writer.log_backlinks_with_cids(&target, &sources, &cids)?;
+        }
         pb.inc(1);
     }
+    writer.finish()?;
     pb.finish();
 
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let cfg = get_app_config()?;
+    let target = std::env::args()
+        .nth(1)
+        .expect("please provide name to compress"); // todo: probably should get some real option parsing
+
     let db = rusqlite::Connection::open(cfg.data_dir.join("db"))?;
-    db.execute(
-        "UPDATE data_stores SET type = 'compacted' WHERE name = ?",
-        [target],
-    )?;
+    setup_db(&db)?;
+
+    let try_lease = |target: &str| {
+        StoreLease::try_compact(&cfg.data_dir.join("live").join(target))?
+            .with_context(|| format!("{target} is still being written to"))
+    };
+    // the lease keeps writers out until the compacted store has replaced it
+    let (target, lock, _lease) = if &target == "oldest" {
+        let (target, lock) = find_live_store_to_compact(&cfg.data_dir, &db)?
+            .context("no live store is waiting to be compacted")?;
+        let lease = try_lease(&target)?;
+        mark_compaction_in_progress(&db, &target)?;
+        (target, lock, lease)
+    } else {
+        let lease = try_lease(&target)?;
+        let lock = claim_live_store(&cfg.data_dir, &db, &target)?
+            .with_context(|| format!("{target} is already being compacted"))?;
+        (target, lock, lease)
+    };
+
+    let live_dir = cfg.data_dir.join("live").join(&target);
+    if let Some(tmp_dir) = prepare_compaction(&cfg.data_dir, &db, &target)? {
+        compact(&live_dir, &tmp_dir)?;
+        for sub_dir in SUBSTORE_DIRS {
+            if live_dir.join(sub_dir).exists() {
+                compact(&live_dir.join(sub_dir), &tmp_dir.join(sub_dir))?;
+            }
+        }
+    } else {
+        println!("{target} was already compacted, finishing up…");
+    }
+
+    finish_compaction(&cfg.data_dir, &db, &target, lock)?;
 
     Ok(())
 }
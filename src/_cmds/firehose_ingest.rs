@@ -1,6 +1,4 @@
-use backshots::{
-    backfill::db::open_backfill_db, firehose::ingest_firehose, get_app_config, AppContext,
-};
+use backshots::{firehose::ingest_firehose, get_app_config, AppContext};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
 #[tokio::main]
@@ -16,11 +14,11 @@ pub async fn main() -> anyhow::Result<()> {
 
     let cfg = get_app_config()?;
     let mut app = AppContext::new(&cfg)?;
-    let backfill_db = open_backfill_db(&cfg)?;
+    app.load_lexicons(&cfg)?;
     let ingest = async move {
         match ingest_firehose(
             &mut app,
-            None, /* Some(&backfill_db) */
+            None, // TODO: Some(&open_backfill_db(&cfg)?) once backfill is hooked up
             "bsky.network",
             443,
             true,
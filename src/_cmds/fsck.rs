@@ -0,0 +1,153 @@
+use std::{collections::HashSet, path::Path};
+
+use anyhow::Result;
+use backshots::{
+    data::{
+        did::resolve_did,
+        record::{
+            resolve_collection, resolve_link_path, resolve_rkey, LinkPath, RecordId,
+            RKEY_FLAG_NOT_TID,
+        },
+    },
+    db::setup_db,
+    get_app_config,
+    storage::{compacted, live, live::SUBSTORE_DIRS},
+    AppContext,
+};
+
+#[derive(Default)]
+struct SeenIds {
+    dids: HashSet<u64>,
+    collections: HashSet<u32>,
+    rkeys: HashSet<u64>,
+    paths: HashSet<LinkPath>,
+}
+
+impl SeenIds {
+    fn add(&mut self, record: &RecordId) {
+        self.dids.insert(record.did);
+        // identity targets are just a did, without a collection or rkey to resolve
+        if record.is_identity() {
+            return;
+        }
+        self.collections.insert(record.collection);
+        // tids are stored inline, so they always resolve
+        if record.rkey & RKEY_FLAG_NOT_TID != 0 {
+            self.rkeys.insert(record.rkey);
+        }
+        if record.path() != 0 {
+            self.paths.insert(record.path());
+        }
+    }
+
+    fn resolve(&self, app: &AppContext) -> Vec<String> {
+        let mut problems = Vec::new();
+        for did in &self.dids {
+            if let Err(e) = resolve_did(app, *did) {
+                problems.push(format!("did {did} doesn't resolve: {e}"));
+            }
+        }
+        for collection in &self.collections {
+            if let Err(e) = resolve_collection(app, *collection) {
+                problems.push(format!("collection {collection} doesn't resolve: {e}"));
+            }
+        }
+        for rkey in &self.rkeys {
+            if let Err(e) = resolve_rkey(app, *rkey) {
+                problems.push(format!("rkey {rkey} doesn't resolve: {e}"));
+            }
+        }
+        for path in &self.paths {
+            if let Err(e) = resolve_link_path(app, *path) {
+                problems.push(format!("link path {path} doesn't resolve: {e}"));
+            }
+        }
+        problems
+    }
+}
+
+fn check_store(store_type: &str, dir: &Path, ids: &mut SeenIds) -> Result<Vec<String>> {
+    match store_type {
+        "live" => live::fsck(dir, |record| ids.add(record)),
+        "compacted" => compacted::fsck(dir, |record| ids.add(record)),
+        _ => anyhow::bail!("unknown store type {store_type}"),
+    }
+}
+
+fn main() -> Result<()> {
+    let cfg = get_app_config()?;
+    let db = rusqlite::Connection::open(cfg.data_dir.join("db"))?;
+    setup_db(&db)?;
+
+    // todo: probably should get some real option parsing
+    let mut names = std::env::args().skip(1).collect::<Vec<_>>();
+    let resolve = names.iter().any(|arg| arg == "--resolve");
+    names.retain(|arg| arg != "--resolve");
+
+    let mut statement = db.prepare("SELECT name, type FROM data_stores ORDER BY id ASC")?;
+    let stores = statement
+        .query_map((), |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?
+        .into_iter()
+        .filter(|(name, _)| names.is_empty() || names.contains(name))
+        .collect::<Vec<_>>();
+    for name in &names {
+        if !stores.iter().any(|(n, _)| n == name) {
+            anyhow::bail!("no such store: {name}");
+        }
+    }
+
+    let mut ids = SeenIds::default();
+    let mut total = 0;
+    for (name, store_type) in stores {
+        let store_dir = cfg.data_dir.join(&store_type).join(&name);
+        let mut dirs = vec![(name.clone(), store_dir.clone())];
+        for sub_dir in SUBSTORE_DIRS {
+            if store_dir.join(sub_dir).exists() {
+                dirs.push((format!("{name}/{sub_dir}"), store_dir.join(sub_dir)));
+            }
+        }
+
+        for (label, dir) in dirs {
+            println!("checking {store_type} store {label}…");
+            let problems = match check_store(&store_type, &dir, &mut ids) {
+                Ok(problems) => problems,
+                Err(e) => vec![format!("could not be checked: {e:?}")],
+            };
+            for problem in &problems {
+                println!("  {label}: {problem}");
+            }
+            total += problems.len();
+        }
+    }
+
+    if resolve {
+        println!("resolving ids…");
+        let app = AppContext::new(&cfg)?;
+        let problems = ids.resolve(&app);
+        for problem in &problems {
+            println!("  {problem}");
+        }
+        total += problems.len();
+    }
+
+    if total > 0 {
+        println!("found {total} problems");
+        std::process::exit(1);
+    }
+    println!("no problems found");
+
+    Ok(())
+}
+
+#[test]
+fn test_seen_ids() {
+    let mut ids = SeenIds::default();
+    ids.add(&RecordId::identity(7));
+    ids.add(&RecordId::new(8, 2, 1));
+    assert_eq!(ids.dids, HashSet::from([7, 8]));
+    assert_eq!(ids.collections, HashSet::from([2]));
+    assert!(ids.rkeys.is_empty() && ids.paths.is_empty());
+}
@@ -1,27 +1,7 @@
-use std::{collections::HashSet, path::Path};
+use std::collections::HashSet;
 
 use anyhow::Result;
-use backshots::{get_app_config, AppContext};
-use nix::{libc::pid_t, sys::signal::kill};
-
-pub fn has_running_procs(store_dir: &Path) -> Result<bool> {
-    for file in std::fs::read_dir(store_dir)?.filter_map(Result::ok) {
-        let Ok(file_name) = file.file_name().into_string() else {
-            continue;
-        };
-        let Some(pid) = file_name.strip_suffix(".pid") else {
-            continue;
-        };
-        let pid: pid_t = pid.parse()?;
-        let pid = nix::unistd::Pid::from_raw(pid);
-        let proc_running = !matches!(kill(pid, None), Err(nix::errno::Errno::ESRCH));
-        if proc_running {
-            return Ok(true);
-        }
-    }
-
-    Ok(false)
-}
+use backshots::{get_app_config, storage::live_guards::StoreLease, AppContext};
 
 fn main() -> Result<()> {
     let cfg = get_app_config()?;
@@ -48,9 +28,10 @@ fn main() -> Result<()> {
         }
 
         let store_dir = live_dir.join(&store);
-        if has_running_procs(&store_dir)? {
+        // held until it's gone, so nobody can start using it in the meantime
+        let Some(_lease) = StoreLease::try_remove(&store_dir)? else {
             continue;
-        }
+        };
 
         println!("cleaning up: {}…", store.to_string_lossy());
         std::fs::remove_dir_all(store_dir)?;
@@ -0,0 +1,37 @@
+use anyhow::{Context, Result};
+use backshots::{
+    db::setup_db,
+    get_app_config,
+    storage::merge::{
+        claim_stores, count_merge_input_entries, find_merge_candidates, merge_stores,
+    },
+};
+use indicatif::ProgressBar;
+
+fn main() -> Result<()> {
+    let cfg = get_app_config()?;
+    let mut db = rusqlite::Connection::open(cfg.data_dir.join("db"))?;
+    setup_db(&db)?;
+
+    // todo: probably should get some real option parsing
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let names = if args == ["auto"] {
+        find_merge_candidates(&cfg.data_dir, &db)?.context("no size tier is full yet")?
+    } else {
+        args
+    };
+    if names.len() < 2 {
+        anyhow::bail!("please provide at least two compacted stores to merge (or 'auto')");
+    }
+
+    let locks = claim_stores(&cfg.data_dir, &mut db, &names)?;
+    println!("merging {}…", names.join(", "));
+
+    let pb = ProgressBar::new(count_merge_input_entries(&cfg.data_dir, &names)?);
+    let merged = merge_stores(&cfg.data_dir, &mut db, &names, locks, || pb.inc(1))?;
+    pb.finish();
+
+    println!("merged {} stores into {merged}", names.len());
+
+    Ok(())
+}
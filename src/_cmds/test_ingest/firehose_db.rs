@@ -1,7 +1,12 @@
+use std::sync::Arc;
+
 use anyhow::Result;
 use backshots::{
     data::at_uri::parse_at_uri,
-    ingest::{common::handle_backlinks, record::get_backlinks},
+    ingest::{
+        common::{handle_backlinks, handle_identity_links},
+        record::get_links,
+    },
     storage::live::LiveStorageWriter,
     AppContext,
 };
@@ -14,6 +19,7 @@ pub fn ingest_firehose_db(app: &mut AppContext, storage: &mut LiveStorageWriter)
     )?;
     let mut stmt = snapshot.prepare("SELECT seq, aturi, value FROM records")?;
     let mut i = 0;
+    let lexicons = Arc::clone(&app.lexicons);
     for row in stmt.query(())?.mapped(|row| {
         let seq = row.get::<_, u64>(0)?;
         let aturi = row.get::<_, String>(1);
@@ -41,8 +47,9 @@ pub fn ingest_firehose_db(app: &mut AppContext, storage: &mut LiveStorageWriter)
             continue;
         };
 
-        let backlinks = get_backlinks(&ipld)?;
+        let (backlinks, identity_links) = get_links(&lexicons, collection, &ipld)?;
         let _ = handle_backlinks(app, storage, repo, collection, rkey, backlinks);
+        let _ = handle_identity_links(app, storage, repo, collection, rkey, identity_links);
 
         i += 1;
         if i % 4096 == 0 {
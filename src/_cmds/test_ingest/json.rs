@@ -12,10 +12,15 @@ use backshots::{
         did::encode_did,
         record::{encode_collection, encode_rkey, RecordId},
     },
+    ingest::common::handle_delete,
     storage::live::LiveStorageWriter,
     AppContext,
 };
 
+// the dumps this reads were made from likes, and only some of them write the record's
+// path (`collection/rkey`) rather than just its rkey
+const DEFAULT_COLLECTION: &str = "app.bsky.feed.like";
+
 #[derive(Debug)]
 pub enum Action {
     Create(CreateEntry),
@@ -25,6 +30,7 @@ pub enum Action {
 #[derive(Debug)]
 pub struct CreateEntry {
     pub did: String,
+    pub collection: String,
     pub rkey: String,
     pub uri: String,
 }
@@ -32,6 +38,7 @@ pub struct CreateEntry {
 #[derive(Debug)]
 pub struct DeleteEntry {
     pub did: String,
+    pub collection: String,
     pub rkey: String,
 }
 
@@ -46,13 +53,26 @@ impl FromStr for Action {
         }
         let action = String::try_from(entry[0].clone())?;
         let did = String::try_from(entry[1].clone())?;
-        let rkey = String::try_from(entry[2].clone())?;
+        let path = String::try_from(entry[2].clone())?;
+        let (collection, rkey) = match path.split_once('/') {
+            Some((collection, rkey)) => (collection.to_string(), rkey.to_string()),
+            None => (DEFAULT_COLLECTION.to_string(), path),
+        };
         match action.as_str() {
             "c" => {
                 let uri = String::try_from(entry[3].clone())?;
-                Ok(Action::Create(CreateEntry { did, rkey, uri }))
+                Ok(Action::Create(CreateEntry {
+                    did,
+                    collection,
+                    rkey,
+                    uri,
+                }))
             }
-            "d" => Ok(Action::Delete(DeleteEntry { did, rkey })),
+            "d" => Ok(Action::Delete(DeleteEntry {
+                did,
+                collection,
+                rkey,
+            })),
             _ => Err(anyhow!("need 'c' or 'd' for entry action type")),
         }
     }
@@ -69,15 +89,21 @@ pub fn ingest_json(app: &mut AppContext, storage: &mut LiveStorageWriter) -> Res
         let action: Action = line.parse()?;
         let action = match action {
             Action::Create(c) => c,
-            Action::Delete(_) => continue,
+            Action::Delete(d) => {
+                handle_delete(app, storage, &d.did, &d.collection, &d.rkey)?;
+                continue;
+            }
         };
 
         let source = RecordId::new(
             encode_did(app, &action.did)?,
-            encode_collection(app, "app.bsky.feed.like")?,
+            encode_collection(app, &action.collection)?,
             encode_rkey(app, &action.rkey)?,
         );
-        let source_display = format!("at://{}/app.bsky.feed.like/{}", &action.did, &action.rkey);
+        let source_display = format!(
+            "at://{}/{}/{}",
+            &action.did, &action.collection, &action.rkey
+        );
         let target = RecordId::from_at_uri(app, &action.uri)?;
         storage.log_backlink(&target, &source)?;
         app.backlinks_counter.add(1);
@@ -19,6 +19,7 @@ fn main() -> Result<()> {
 
     let cfg = get_app_config()?;
     let mut app = AppContext::new(&cfg)?;
+    app.load_lexicons(&cfg)?;
     let mut storage = LiveWriteHandle::latest(&app)?;
     // json::ingest_json(&mut app, &mut storage)?;
 
@@ -14,6 +14,17 @@ pub struct CidV1<const HASH_SIZE: usize> {
 }
 pub type CidV1Sha256 = CidV1<32>;
 
+// what stores keep of the cid a backlink points at: the start of its sha256 hash, which is
+// plenty to tell revisions of the same record apart. 0 means no cid was recorded
+pub type CidHash = u64;
+
+impl CidV1Sha256 {
+    pub fn truncated_hash(&self) -> CidHash {
+        let hash = self.hash;
+        u64::from_le_bytes(hash[..8].try_into().unwrap()).max(1)
+    }
+}
+
 pub fn cidv1_meta(version: u8, codec: u8, hash_type: u8) -> u32 {
     version as u32 | ((codec as u32) << 8) | ((hash_type as u32) << 16)
 }
@@ -24,7 +35,7 @@ impl FromStr for CidV1Sha256 {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let (_base, data) = multibase::decode(s)?;
 
-        let [version, codec, hash_type] = data[..3] else {
+        let Some(&[version, codec, hash_type, hash_size]) = data.get(..4) else {
             anyhow::bail!("not enough data")
         };
         if version != 1 {
@@ -37,7 +48,6 @@ impl FromStr for CidV1Sha256 {
             );
         }
 
-        let hash_size = data[3];
         if hash_size != 32 {
             anyhow::bail!(
                 "multihash hash size was incorrect (expected 32, got {})",
@@ -45,8 +55,10 @@ impl FromStr for CidV1Sha256 {
             );
         }
 
-        let mut hash: [u8; 32] = [0; 32];
-        hash.copy_from_slice(&data[4..4 + 32]);
+        let Some(hash) = data.get(4..4 + 32) else {
+            anyhow::bail!("not enough data")
+        };
+        let hash: [u8; 32] = hash.try_into()?;
 
         Ok(CidV1Sha256 {
             meta: cidv1_meta(version, codec, hash_type),
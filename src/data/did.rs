@@ -6,6 +6,23 @@ pub const DID_MASK: u64 = 0x0000FFFFFFFFFFFF;
 // did:web, and did:plc past 2^48 (≈ 280 trillion)
 pub const DID_FLAG_NON_STANDARD: u64 = 1 << 63;
 
+/// whether `s` looks like a did (`did:<method>:<identifier>`), without resolving it
+pub fn is_did(s: &str) -> bool {
+    let Some((method, identifier)) = s.strip_prefix("did:").and_then(|rest| rest.split_once(':'))
+    else {
+        return false;
+    };
+    !method.is_empty()
+        && method
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit())
+        && !identifier.is_empty()
+        && !identifier.ends_with(':')
+        && identifier
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b".-_:%".contains(&b))
+}
+
 pub fn resolve_did(app: &AppContext, did: u64) -> Result<String> {
     if did & DID_FLAG_NON_STANDARD == 0 {
         // return app.async_block_on(app.zplc_resolver.zplc_to_did(did));
@@ -1,3 +1,5 @@
+use std::collections::HashSet;
+
 use anyhow::{Context, Result};
 use zerocopy::{FromBytes, Immutable, IntoBytes};
 
@@ -10,11 +12,38 @@ use super::{at_uri::parse_at_uri, did::encode_did};
 
 // this is just an index into the collections table
 pub type RecordCollection = u32;
+// which the table never hands out, so it's free to mark identity targets (see RecordId::identity)
+const IDENTITY_COLLECTION: RecordCollection = 0;
+
+// an index into the link_paths table, or 0 for backlinks without a known path (logged before
+// paths were recorded, or from a field that no lexicon describes). the path of a backlink is
+// where its target shows up in the source record (e.g. `reply.parent`). every source only has
+// one backlink per target, so if it shows up in more than one place, only one of them is kept.
+// older backlinks may have all of them, comma-separated and sorted (e.g. `reply.parent,reply.root`)
+pub type LinkPath = u32;
 
 pub const RKEY_FLAG_NOT_TID: u64 = 1 << 63;
 pub const RKEY_DB_MASK: u64 = !RKEY_FLAG_NOT_TID;
 
-#[derive(Clone, Copy, IntoBytes, FromBytes, Immutable, PartialEq, Eq, PartialOrd, Ord)]
+// set on a live store entry once its source record has been deleted
+pub const RECORD_FLAG_DELETED: u32 = 1 << 0;
+// set on a live store entry that doesn't hold a backlink, but links its chain
+// across a gap that is too big for a relative offset
+pub const RECORD_FLAG_JUMP: u32 = 1 << 1;
+// set on a live store entry whose previous entry is too far away for `prev` to point at
+pub const RECORD_FLAG_FAR_PREV: u32 = 1 << 2;
+// set on the target of a compacted store index entry whose link block is compressed
+pub const RECORD_FLAG_COMPRESSED_BLOCK: u32 = 1 << 3;
+// set on the target of a compacted store index entry whose link block stores paths
+pub const RECORD_FLAG_LINK_PATHS: u32 = 1 << 4;
+// set on the target of a compacted store index entry whose link block stores cids
+pub const RECORD_FLAG_CIDS: u32 = 1 << 5;
+
+// the flags of a backlink's source hold its LinkPath above the flag bits
+pub const RECORD_PATH_SHIFT: u32 = 8;
+pub const MAX_LINK_PATH: LinkPath = LinkPath::MAX >> RECORD_PATH_SHIFT;
+
+#[derive(Clone, Copy, IntoBytes, FromBytes, Immutable, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(C, packed)]
 pub struct RecordId {
     pub rkey: u64,
@@ -32,6 +61,31 @@ impl RecordId {
             _flags: 0.into(),
         }
     }
+
+    /// the target of a record-to-identity link (e.g. a follow's `subject`), which stands for
+    /// the did itself rather than any of its records
+    pub fn identity(did: u64) -> Self {
+        Self::new(did, IDENTITY_COLLECTION, 0)
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.collection == IDENTITY_COLLECTION
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self._flags.0 & RECORD_FLAG_DELETED != 0
+    }
+
+    pub fn path(&self) -> LinkPath {
+        self._flags.0 >> RECORD_PATH_SHIFT
+    }
+
+    pub fn with_path(mut self, path: LinkPath) -> Self {
+        debug_assert!(path <= MAX_LINK_PATH, "link path id too large");
+        self._flags.0 =
+            (self._flags.0 & ((1 << RECORD_PATH_SHIFT) - 1)) | path << RECORD_PATH_SHIFT;
+        self
+    }
 }
 
 impl RecordId {
@@ -53,6 +107,7 @@ impl std::fmt::Debug for RecordId {
             .field("rkey", &u64::from(self.rkey))
             .field("collection", &u32::from(self.collection))
             .field("did", &u64::from(self.did))
+            .field("path", &self.path())
             .finish()
     }
 }
@@ -77,6 +132,23 @@ pub fn encode_rkey(app: &AppContext, rkey: &str) -> Result<u64> {
     Ok(rkey_id | RKEY_FLAG_NOT_TID)
 }
 
+/// like encode_rkey, but returns None instead of adding rkeys we haven't seen before
+pub fn encode_existing_rkey(app: &AppContext, rkey: &str) -> Result<Option<u64>> {
+    if is_tid(rkey) {
+        return Ok(Some(s32decode(rkey)));
+    }
+
+    match app.db.query_row(
+        "SELECT id FROM outline_rkeys WHERE rkey = ?",
+        [rkey],
+        |row| row.get::<_, u64>(0),
+    ) {
+        Ok(rkey_id) => Ok(Some(rkey_id | RKEY_FLAG_NOT_TID)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
 pub fn resolve_rkey(app: &AppContext, rkey_id: u64) -> Result<String> {
     if rkey_id & RKEY_FLAG_NOT_TID == 0 {
         return Ok(s32encode(rkey_id));
@@ -117,6 +189,29 @@ pub fn encode_collection(app: &mut AppContext, collection: &str) -> Result<Recor
     Ok(id)
 }
 
+/// like encode_collection, but returns None instead of adding collections we haven't seen before
+pub fn encode_existing_collection(
+    app: &mut AppContext,
+    collection: &str,
+) -> Result<Option<RecordCollection>> {
+    if let Some(cached) = app.caches.collection.get(collection) {
+        return Ok(Some(*cached));
+    }
+
+    match app.db.query_row(
+        "SELECT id FROM collections WHERE collection = ?",
+        [collection],
+        |row| row.get::<_, u32>(0),
+    ) {
+        Ok(id) => {
+            app.caches.collection.insert(collection.into(), id);
+            Ok(Some(id))
+        }
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
 pub fn resolve_collection(app: &AppContext, coll: RecordCollection) -> Result<String> {
     let collection: String = app
         .db
@@ -130,6 +225,67 @@ pub fn resolve_collection(app: &AppContext, coll: RecordCollection) -> Result<St
     Ok(collection)
 }
 
+pub fn encode_link_path(app: &mut AppContext, path: &str) -> Result<LinkPath> {
+    if let Some(cached) = app.caches.link_path.get(path) {
+        return Ok(*cached);
+    }
+
+    app.db
+        .execute("INSERT OR IGNORE INTO link_paths (path) VALUES (?)", [path])?;
+    let id: LinkPath =
+        app.db
+            .query_row("SELECT id FROM link_paths WHERE path = ?", [path], |row| {
+                row.get(0)
+            })?;
+    if id > MAX_LINK_PATH {
+        anyhow::bail!("ran out of link path ids at {path}");
+    }
+    app.caches.link_path.insert(path.into(), id);
+    Ok(id)
+}
+
+/// like `encode_link_path`, but returns `None` instead of adding paths that aren't there yet
+pub fn encode_existing_link_path(app: &mut AppContext, path: &str) -> Result<Option<LinkPath>> {
+    if let Some(cached) = app.caches.link_path.get(path) {
+        return Ok(Some(*cached));
+    }
+
+    match app
+        .db
+        .query_row("SELECT id FROM link_paths WHERE path = ?", [path], |row| {
+            row.get::<_, LinkPath>(0)
+        }) {
+        Ok(id) if id <= MAX_LINK_PATH => {
+            app.caches.link_path.insert(path.into(), id);
+            Ok(Some(id))
+        }
+        Ok(_) | Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn resolve_link_path(app: &AppContext, path: LinkPath) -> Result<String> {
+    let path: String = app
+        .db
+        .query_row("SELECT path FROM link_paths WHERE id = ?", [path], |row| {
+            row.get(0)
+        })
+        .context("could not find link path id in link_paths table")?;
+
+    Ok(path)
+}
+
+/// every LinkPath that `path` is (one of the paths) in
+pub fn find_link_paths(app: &AppContext, path: &str) -> Result<HashSet<LinkPath>> {
+    let mut statement = app.db.prepare_cached(
+        "SELECT id FROM link_paths WHERE instr(',' || path || ',', ',' || ? || ',') > 0",
+    )?;
+    let ids = statement
+        .query_map([path], |row| row.get(0))?
+        .collect::<rusqlite::Result<HashSet<_>>>()?;
+    Ok(ids)
+}
+
 #[derive(Clone, Copy, IntoBytes, FromBytes, Immutable)]
 #[repr(transparent)]
 pub struct RecordIdFlags(pub u32);
@@ -149,6 +305,10 @@ impl PartialOrd for RecordIdFlags {
         Some(self.cmp(other))
     }
 }
+// flags don't take part in comparisons, so they can't take part in hashing either
+impl std::hash::Hash for RecordIdFlags {
+    fn hash<H: std::hash::Hasher>(&self, _state: &mut H) {}
+}
 impl From<u32> for RecordIdFlags {
     fn from(value: u32) -> Self {
         Self(value)
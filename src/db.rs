@@ -19,4 +19,5 @@ pub fn setup_db(db: &DbConnection) -> Result<()> {
 pub struct DbCaches {
     pub did: HashMap<String, u64>,
     pub collection: HashMap<String, u32>,
+    pub link_path: HashMap<String, u32>,
 }
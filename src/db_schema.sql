@@ -17,6 +17,25 @@ CREATE TABLE IF NOT EXISTS collections (
   id INTEGER PRIMARY KEY,
   collection TEXT UNIQUE NOT NULL
 ) STRICT;
+CREATE TABLE IF NOT EXISTS link_paths (
+  id INTEGER PRIMARY KEY,
+  path TEXT UNIQUE NOT NULL -- e.g. 'reply.parent' (or 'reply.parent,reply.root' from before paths were split)
+) STRICT;
+-- deletes that a compaction or merge may have missed (see storage::deletion).
+-- AUTOINCREMENT, since ids must never be reused once a mark has been taken
+CREATE TABLE IF NOT EXISTS pending_deletes (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  did INTEGER NOT NULL,
+  collection INTEGER NOT NULL,
+  rkey INTEGER NOT NULL,
+  applied INTEGER NOT NULL DEFAULT 0, -- whether it has reached every store listed at the time
+  attempts INTEGER NOT NULL DEFAULT 1
+) STRICT;
+CREATE INDEX IF NOT EXISTS pending_deletes_unapplied ON pending_deletes (id) WHERE applied = 0;
+CREATE TABLE IF NOT EXISTS pending_delete_marks (
+  name TEXT NOT NULL PRIMARY KEY, -- the store being written
+  mark INTEGER NOT NULL -- the last pending delete that it is known to have
+) STRICT;
 CREATE TABLE IF NOT EXISTS data_stores (
   id INTEGER PRIMARY KEY,
   name TEXT UNIQUE NOT NULL,
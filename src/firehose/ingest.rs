@@ -12,6 +12,7 @@ use crate::backfill::db::convert_did_to_db;
 use crate::car::CarFile;
 use crate::data::did::{encode_did, encode_existing_did};
 use crate::ingest::carslice::handle_carslice;
+use crate::ingest::common::handle_delete;
 use crate::mst::SignedCommitNode;
 use crate::storage::live_guards::LiveWriteHandle;
 use crate::AppContext;
@@ -72,7 +73,7 @@ pub async fn ingest_firehose(
             match response {
                 Some(Ok(tokio_tungstenite::tungstenite::Message::Binary(bytes))) => {
                     event_count += 1;
-                    if event_count % 128 == 0 {
+                    if event_count.is_multiple_of(128) {
                         event_count = 0;
                         if LiveWriteHandle::latest_id(app).ok() != Some(storage.store_id) {
                             tracing::info!("rolling over live storage");
@@ -127,10 +128,27 @@ pub fn ingest_commit<R: Read + Seek>(
                 let Some(cid) = op.cid else {
                     continue;
                 };
+                // skipped here so that the block never even gets decoded
+                let collection = op.path.split_once('/').map_or("", |(c, _)| c);
+                if !app.source_collections.allows(collection) {
+                    continue;
+                }
                 records.insert(cid, op.path);
             }
             "delete" => {
-                // TODO: handle deletes? this would be like a full scan every time :/
+                let Some((collection, rkey)) = op.path.split_once('/') else {
+                    continue;
+                };
+                // ops apply in order, so a record created earlier in this commit has to be
+                // there before its delete can find it
+                if !records.is_empty() {
+                    let records = std::mem::take(&mut records);
+                    handle_carslice(app, storage, repo.clone(), reader, car_file, &records)?;
+                }
+                // not filtered, since there may be backlinks from before the filters changed
+                if let Err(e) = handle_delete(app, storage, &repo, collection, rkey) {
+                    tracing::warn!(%repo, path = %op.path, "failed to handle delete: {e:?}");
+                }
             }
             _ => tracing::warn!("unknown op action: {}", &op.action),
         }
@@ -1,6 +1,7 @@
 use std::{
     collections::BTreeMap,
     io::{Read, Seek},
+    sync::Arc,
 };
 
 use anyhow::Result;
@@ -8,7 +9,10 @@ use ipld_core::{cid::Cid, ipld::Ipld};
 
 use crate::{car::CarFile, storage::live::LiveStorageWriter, AppContext};
 
-use super::{common::handle_backlinks, record::get_backlinks};
+use super::{
+    common::{handle_backlinks, handle_identity_links},
+    record::get_links,
+};
 
 pub fn handle_carslice<R: Read + Seek>(
     app: &mut AppContext,
@@ -18,10 +22,15 @@ pub fn handle_carslice<R: Read + Seek>(
     car_file: &CarFile,
     records: &BTreeMap<Cid, String>,
 ) -> Result<()> {
+    // so that it can be borrowed alongside `app`
+    let lexicons = Arc::clone(&app.lexicons);
     for (cid, path) in records {
         let Some((collection, rkey)) = path.split_once('/') else {
             continue;
         };
+        if !app.source_collections.allows(collection) {
+            continue;
+        }
 
         let cbor = match car_file.read_block(reader, cid) {
             Ok(cbor) => cbor,
@@ -32,8 +41,9 @@ pub fn handle_carslice<R: Read + Seek>(
         };
 
         let ipld = serde_ipld_dagcbor::from_slice::<Ipld>(&cbor)?;
-        let backlinks = get_backlinks(&ipld)?;
+        let (backlinks, identity_links) = get_links(&lexicons, collection, &ipld)?;
         handle_backlinks(app, storage, &repo, collection, rkey, backlinks)?;
+        handle_identity_links(app, storage, &repo, collection, rkey, identity_links)?;
     }
 
     app.backlinks_counter.flush(&app.db)?;
@@ -1,25 +1,56 @@
 use anyhow::Result;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 
 use crate::{
     data::{
         at_uri::parse_at_uri,
-        did::encode_did,
-        record::{encode_collection, encode_rkey, RecordId},
+        cid::{CidHash, CidV1Sha256},
+        did::{encode_did, encode_existing_did},
+        record::{
+            encode_collection, encode_existing_collection, encode_existing_link_path,
+            encode_existing_rkey, encode_link_path, encode_rkey, LinkPath, RecordId,
+        },
     },
-    storage::live::LiveStorageWriter,
+    storage::{deletion::delete_source, live::LiveStorageWriter},
     AppContext,
 };
 
+// anyone can put whatever keys they like in a record, so paths only get interned when the
+// collection's lexicon has them. paths that something else put in link_paths are still used,
+// but anything else is stored without one (path 0). a source links to a target only once, so
+// when it does so from several places, the first of them that we know of is the one it gets
+fn link_path(app: &mut AppContext, collection: &str, paths: &BTreeSet<String>) -> LinkPath {
+    for path in paths {
+        let in_lexicon = app
+            .lexicons
+            .get(collection)
+            .is_some_and(|schema| schema.fields.contains_key(path));
+        let id = match in_lexicon {
+            true => encode_link_path(app, path).map(Some),
+            false => encode_existing_link_path(app, path),
+        };
+        match id {
+            Ok(Some(id)) => return id,
+            Ok(None) => {}
+            Err(e) => tracing::warn!("failed to encode link path {path}: {:?}", e),
+        }
+    }
+    0
+}
+
 pub fn handle_backlinks(
     app: &mut AppContext,
     storage: &mut LiveStorageWriter,
     repo: &str,
     collection: &str,
     rkey: &str,
-    backlinks: HashSet<(/* cid */ &str, /* uri */ &str)>,
+    backlinks: HashSet<(
+        /* cid */ Option<&str>,
+        /* uri */ &str,
+        /* path */ String,
+    )>,
 ) -> Result<()> {
-    if backlinks.is_empty() {
+    if backlinks.is_empty() || !app.source_collections.allows(collection) {
         return Ok(());
     }
 
@@ -31,7 +62,16 @@ pub fn handle_backlinks(
 
     let source_display = format!("at://{repo}/{collection}/{rkey}");
 
-    for (_cid, uri) in backlinks {
+    // a record that links to the same thing more than once still only gets one backlink to it
+    let mut links_by_uri = BTreeMap::<&str, (BTreeSet<String>, Option<&str>)>::new();
+    for (cid, uri, path) in backlinks {
+        let (paths, uri_cid) = links_by_uri.entry(uri).or_default();
+        paths.insert(path);
+        // bare at-uris don't have one, but a StrongRef to the same record might
+        *uri_cid = uri_cid.or(cid);
+    }
+
+    for (uri, (paths, cid)) in links_by_uri {
         let (target_repo, target_collection, target_rkey) = match parse_at_uri(uri) {
             Ok(x) => x,
             Err(e) => {
@@ -39,6 +79,9 @@ pub fn handle_backlinks(
                 continue;
             }
         };
+        if !app.target_collections.allows(target_collection) {
+            continue;
+        }
 
         // my kingdom for a try block
         #[inline]
@@ -57,10 +100,22 @@ pub fn handle_backlinks(
 
         match create_record_id(app, target_repo, target_collection, target_rkey) {
             Ok(target) => {
-                tracing::debug!(from = source_display, to = uri, "backlink");
+                tracing::debug!(from = source_display, to = uri, ?paths, "backlink");
+                let path = link_path(app, collection, &paths);
+
+                let cid: CidHash = match (app.store_cids, cid) {
+                    (true, Some(cid)) => cid.parse::<CidV1Sha256>().map_or_else(
+                        |e| {
+                            tracing::debug!("not storing cid {cid} of link to {uri}: {:?}", e);
+                            0
+                        },
+                        |cid| cid.truncated_hash(),
+                    ),
+                    _ => 0,
+                };
 
                 // TODO: we probably shouldnt block the runtime like this but whatever
-                storage.log_backlink(&target, &source)?;
+                storage.log_backlink_with_cid(&target, &source.with_path(path), cid)?;
                 app.backlinks_counter.add(1);
             }
             Err(e) => tracing::warn!("failed to create RecordId: {:?}", e),
@@ -69,3 +124,78 @@ pub fn handle_backlinks(
 
     Ok(())
 }
+
+pub fn handle_identity_links(
+    app: &mut AppContext,
+    storage: &mut LiveStorageWriter,
+    repo: &str,
+    collection: &str,
+    rkey: &str,
+    links: HashSet<(/* did */ &str, /* path */ String)>,
+) -> Result<()> {
+    // identities aren't in a collection, so only the source filter applies to these
+    if links.is_empty() || !app.source_collections.allows(collection) {
+        return Ok(());
+    }
+
+    let source = RecordId::new(
+        encode_did(app, repo)?,
+        encode_collection(app, collection)?,
+        encode_rkey(app, rkey)?,
+    );
+
+    let source_display = format!("at://{repo}/{collection}/{rkey}");
+
+    // just like backlinks, one link per did no matter how many times it shows up
+    let mut paths_by_did = BTreeMap::<&str, BTreeSet<String>>::new();
+    for (did, path) in links {
+        paths_by_did.entry(did).or_default().insert(path);
+    }
+
+    for (did, paths) in paths_by_did {
+        let target = match encode_did(app, did) {
+            Ok(x) => x,
+            Err(e) => {
+                tracing::warn!("failed to encode did {did}: {:?}", e);
+                continue;
+            }
+        };
+
+        tracing::debug!(from = source_display, to = did, ?paths, "identity link");
+        let path = link_path(app, collection, &paths);
+
+        storage.log_identity_link(target, &source.with_path(path))?;
+        app.backlinks_counter.add(1);
+    }
+
+    Ok(())
+}
+
+pub fn handle_delete(
+    app: &mut AppContext,
+    storage: &mut LiveStorageWriter,
+    repo: &str,
+    collection: &str,
+    rkey: &str,
+) -> Result<()> {
+    // nothing can link from a record whose ids we've never stored,
+    // so there is no need to add them just to find that out
+    let Some(did) = encode_existing_did(app, repo)? else {
+        return Ok(());
+    };
+    let Some(collection_id) = encode_existing_collection(app, collection)? else {
+        return Ok(());
+    };
+    let Some(rkey_id) = encode_existing_rkey(app, rkey)? else {
+        return Ok(());
+    };
+    let source = RecordId::new(did, collection_id, rkey_id);
+
+    let deleted = delete_source(app, storage, &source)?;
+    if deleted > 0 {
+        let source_display = format!("at://{repo}/{collection}/{rkey}");
+        tracing::debug!(from = source_display, deleted, "deleted backlinks");
+    }
+
+    Ok(())
+}
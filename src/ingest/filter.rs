@@ -0,0 +1,91 @@
+use anyhow::Result;
+
+/// which collections a deployment cares about, as glob patterns (`*` matches anything,
+/// dots included, so `app.bsky.feed.*` covers every feed collection).
+/// a collection gets through if it matches an allow pattern (or there aren't any),
+/// and doesn't match a deny pattern
+#[derive(Debug, Clone, Default)]
+pub struct CollectionFilter {
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+impl CollectionFilter {
+    pub fn new(allow: Vec<String>, deny: Vec<String>) -> Self {
+        Self { allow, deny }
+    }
+
+    /// reads comma-separated patterns from `{prefix}_ALLOW` and `{prefix}_DENY`
+    pub fn from_env(prefix: &str) -> Result<Self> {
+        let patterns = |var: String| -> Result<Vec<String>> {
+            match std::env::var(&var) {
+                Ok(value) => Ok(value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|pattern| !pattern.is_empty())
+                    .map(String::from)
+                    .collect()),
+                Err(std::env::VarError::NotPresent) => Ok(Vec::new()),
+                Err(e) => Err(anyhow::anyhow!("can't read {var}: {e}")),
+            }
+        };
+        Ok(Self::new(
+            patterns(format!("{prefix}_ALLOW"))?,
+            patterns(format!("{prefix}_DENY"))?,
+        ))
+    }
+
+    pub fn allows(&self, collection: &str) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|p| glob_match(p, collection)))
+            && !self.deny.iter().any(|p| glob_match(p, collection))
+    }
+}
+
+fn glob_match(pattern: &str, s: &str) -> bool {
+    let (pattern, s) = (pattern.as_bytes(), s.as_bytes());
+    // where to pick back up if what came after the last `*` doesn't pan out
+    let mut star = None::<(usize, usize)>;
+    let (mut p, mut i) = (0, 0);
+    while i < s.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, i));
+                p += 1;
+            }
+            Some(&c) if c == s[i] => {
+                p += 1;
+                i += 1;
+            }
+            _ => match star {
+                Some((star_p, star_i)) => {
+                    star = Some((star_p, star_i + 1));
+                    p = star_p + 1;
+                    i = star_i + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[test]
+fn test_collection_filter() {
+    assert!(glob_match("app.bsky.feed.*", "app.bsky.feed.post"));
+    assert!(glob_match("*.like", "app.bsky.feed.like"));
+    assert!(glob_match("com.*.thing*", "com.example.things"));
+    assert!(!glob_match("app.bsky.feed.*", "app.bsky.graph.follow"));
+    assert!(!glob_match("app.bsky.feed", "app.bsky.feed.post"));
+
+    let everything = CollectionFilter::default();
+    assert!(everything.allows("app.bsky.feed.post"));
+
+    let filter = CollectionFilter::new(
+        vec!["app.bsky.feed.*".into(), "com.example.*".into()],
+        vec!["app.bsky.feed.threadgate".into()],
+    );
+    assert!(filter.allows("app.bsky.feed.like"));
+    assert!(filter.allows("com.example.pin"));
+    assert!(!filter.allows("app.bsky.feed.threadgate"));
+    assert!(!filter.allows("app.bsky.graph.follow"));
+}
@@ -0,0 +1,339 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use tinyjson::JsonValue;
+
+use super::record::MAX_LINK_DEPTH;
+
+// lexicons describe every field of a record, so for collections we have one for, links can
+// be picked out exactly instead of guessing from what the record looks like. they get read
+// from a few directories at startup (see AppConfig::lexicon_dirs); collections without one
+// fall back to the heuristics in ingest::record.
+
+const STRONG_REF: &str = "com.atproto.repo.strongRef#main";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LinkKind {
+    /// a `com.atproto.repo.strongRef`
+    StrongRef,
+    /// a string with `"format": "at-uri"`
+    AtUri,
+    /// a string with `"format": "did"` (or `at-identifier`, when it holds a did)
+    Did,
+    /// a `cid-link`, which points at a block rather than a record. we don't have anything to
+    /// make a backlink to out of these yet, so they only get recognised
+    CidLink,
+}
+
+/// where the links in one collection's records are, as paths like in ingest::record
+#[derive(Debug, Default)]
+pub struct RecordSchema {
+    pub fields: HashMap<String, BTreeSet<LinkKind>>,
+    // every path that some field is below, so extraction knows which parts to skip
+    pub prefixes: HashSet<String>,
+}
+
+impl RecordSchema {
+    fn add(&mut self, path: &str, kind: LinkKind) {
+        self.fields
+            .entry(path.to_string())
+            .or_default()
+            .insert(kind);
+        let mut prefix = path;
+        while let Some(end) = prefix.rfind(['.', '[']) {
+            prefix = &prefix[..end];
+            self.prefixes.insert(prefix.to_string());
+        }
+        self.prefixes.insert(String::new());
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Lexicons {
+    records: HashMap<String, RecordSchema>,
+}
+
+impl Lexicons {
+    /// reads every `.json` file under `dirs`. later directories win when two of them define
+    /// the same lexicon, and missing directories are skipped
+    pub fn load(dirs: &[PathBuf]) -> Result<Self> {
+        let mut documents = HashMap::<String, JsonValue>::new();
+        for dir in dirs {
+            if !dir.exists() {
+                tracing::debug!(?dir, "lexicon directory doesn't exist, skipping it");
+                continue;
+            }
+            let mut files = Vec::new();
+            find_json_files(dir, &mut files)?;
+            for file in files {
+                match read_document(&file) {
+                    Ok((id, doc)) => {
+                        documents.insert(id, doc);
+                    }
+                    Err(e) => tracing::warn!(?file, "skipping lexicon: {e:?}"),
+                }
+            }
+        }
+        Ok(Self::from_documents(&documents))
+    }
+
+    /// works out the links in every record type out of `documents` (by lexicon id)
+    pub fn from_documents(documents: &HashMap<String, JsonValue>) -> Self {
+        let mut records = HashMap::new();
+        for (id, doc) in documents {
+            let Some(main) = field(doc, "defs").and_then(|defs| field(defs, "main")) else {
+                continue;
+            };
+            if str_field(main, "type") != Some("record") {
+                continue;
+            }
+            let mut schema = RecordSchema::default();
+            let mut resolver = Resolver {
+                documents,
+                schema: &mut schema,
+                stack: Vec::new(),
+            };
+            if let Some(record) = field(main, "record") {
+                resolver.walk(id, record, &mut String::new());
+            }
+            records.insert(id.clone(), schema);
+        }
+        Self { records }
+    }
+
+    pub fn get(&self, collection: &str) -> Option<&RecordSchema> {
+        self.records.get(collection)
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+fn find_json_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_json_files(&path, files)?;
+        } else if path.extension().is_some_and(|ext| ext == "json") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn read_document(file: &Path) -> Result<(String, JsonValue)> {
+    let doc: JsonValue = std::fs::read_to_string(file)?.parse()?;
+    let id = str_field(&doc, "id").context("lexicon has no id")?;
+    Ok((id.to_string(), doc))
+}
+
+fn field<'a>(value: &'a JsonValue, key: &str) -> Option<&'a JsonValue> {
+    match value {
+        JsonValue::Object(object) => object.get(key),
+        _ => None,
+    }
+}
+
+fn str_field<'a>(value: &'a JsonValue, key: &str) -> Option<&'a str> {
+    field(value, key)
+        .and_then(|value| value.get::<String>())
+        .map(String::as_str)
+}
+
+struct Resolver<'a> {
+    documents: &'a HashMap<String, JsonValue>,
+    schema: &'a mut RecordSchema,
+    // the defs we are inside of. they are allowed to refer to themselves, but records
+    // rarely do, so links under a def that's already being walked just get missed
+    stack: Vec<String>,
+}
+
+impl Resolver<'_> {
+    // `id` is the lexicon that `def` is in, for resolving local refs
+    fn walk(&mut self, id: &str, def: &JsonValue, path: &mut String) {
+        if self.stack.len() >= MAX_LINK_DEPTH {
+            return;
+        }
+        let len = path.len();
+        match str_field(def, "type") {
+            Some("object") => {
+                let Some(JsonValue::Object(properties)) = field(def, "properties") else {
+                    return;
+                };
+                for (key, property) in properties {
+                    if !path.is_empty() {
+                        path.push('.');
+                    }
+                    path.push_str(key);
+                    self.walk(id, property, path);
+                    path.truncate(len);
+                }
+            }
+            Some("array") => {
+                if let Some(items) = field(def, "items") {
+                    path.push_str("[]");
+                    self.walk(id, items, path);
+                    path.truncate(len);
+                }
+            }
+            Some("ref") => {
+                if let Some(name) = str_field(def, "ref") {
+                    self.walk_ref(id, name, path);
+                }
+            }
+            Some("union") => {
+                if let Some(JsonValue::Array(refs)) = field(def, "refs") {
+                    for name in refs.iter().filter_map(|name| name.get::<String>()) {
+                        self.walk_ref(id, name, path);
+                    }
+                }
+            }
+            Some("string") => match str_field(def, "format") {
+                Some("at-uri") => self.schema.add(path, LinkKind::AtUri),
+                Some("did" | "at-identifier") => self.schema.add(path, LinkKind::Did),
+                _ => {}
+            },
+            Some("cid-link") => self.schema.add(path, LinkKind::CidLink),
+            _ => {}
+        }
+    }
+
+    fn walk_ref(&mut self, id: &str, name: &str, path: &mut String) {
+        // `#def` is in the same lexicon, and a bare id means its `main`
+        let full_name = match name.split_once('#') {
+            Some(("", def)) => format!("{id}#{def}"),
+            Some(_) => name.to_string(),
+            None => format!("{name}#main"),
+        };
+        if full_name == STRONG_REF {
+            self.schema.add(path, LinkKind::StrongRef);
+            return;
+        }
+        if self.stack.contains(&full_name) {
+            return;
+        }
+
+        let (ref_id, def_name) = full_name.split_once('#').unwrap();
+        let Some(def) = self
+            .documents
+            .get(ref_id)
+            .and_then(|doc| field(doc, "defs"))
+            .and_then(|defs| field(defs, def_name))
+        else {
+            tracing::debug!(lexicon = id, "can't find {full_name}, skipping it");
+            return;
+        };
+        self.stack.push(full_name.clone());
+        self.walk(ref_id, def, path);
+        self.stack.pop();
+    }
+}
+
+#[test]
+fn test_lexicons() -> Result<()> {
+    use super::record::get_links_with_schema;
+    use crate::test_util::map;
+    use ipld_core::ipld::Ipld;
+
+    let dir = crate::test_util::TempDir::new();
+    let write = |file: &str, json: &str| -> Result<()> {
+        let file = dir.join(file);
+        std::fs::create_dir_all(file.parent().unwrap())?;
+        Ok(std::fs::write(file, json)?)
+    };
+    write(
+        "atrium/com/atproto/repo/strongRef.json",
+        r#"{"lexicon": 1, "id": "com.atproto.repo.strongRef", "defs": {"main": {
+            "type": "object", "properties": {
+                "uri": {"type": "string", "format": "at-uri"},
+                "cid": {"type": "string", "format": "cid"}}}}}"#,
+    )?;
+    // a third-party lexicon, in a directory of its own
+    write(
+        "extra/com.example.pin.json",
+        r##"{"lexicon": 1, "id": "com.example.pin", "defs": {
+            "main": {"type": "record", "key": "tid", "record": {
+                "type": "object", "properties": {
+                    "subject": {"type": "ref", "ref": "com.atproto.repo.strongRef"},
+                    "board": {"type": "string", "format": "at-uri"},
+                    "note": {"type": "string"},
+                    "by": {"type": "array", "items": {"type": "ref", "ref": "#person"}},
+                    "thumb": {"type": "cid-link"},
+                    "embed": {"type": "union", "refs": ["#person", "com.example.pin#pinned"]}}}},
+            "person": {"type": "object", "properties": {
+                "did": {"type": "string", "format": "did"},
+                "friend": {"type": "ref", "ref": "#person"}}},
+            "pinned": {"type": "object", "properties": {
+                "pin": {"type": "ref", "ref": "com.atproto.repo.strongRef"}}}}}"##,
+    )?;
+    write("extra/broken.json", "{")?;
+
+    let lexicons = Lexicons::load(&[dir.join("atrium"), dir.join("extra"), dir.join("nope")])?;
+    assert_eq!(lexicons.len(), 1);
+    let schema = lexicons.get("com.example.pin").unwrap();
+    let mut fields = schema
+        .fields
+        .iter()
+        .flat_map(|(path, kinds)| kinds.iter().map(move |kind| (path.as_str(), *kind)))
+        .collect::<Vec<_>>();
+    fields.sort();
+    assert_eq!(
+        fields,
+        [
+            ("board", LinkKind::AtUri),
+            ("by[].did", LinkKind::Did),
+            ("embed.did", LinkKind::Did),
+            ("embed.pin", LinkKind::StrongRef),
+            ("subject", LinkKind::StrongRef),
+            ("thumb", LinkKind::CidLink),
+        ]
+    );
+    // #person refers to itself, which doesn't get followed
+    assert!(schema.prefixes.contains("by[]") && schema.prefixes.contains("embed"));
+
+    // only what the lexicon says is a link gets picked up
+    let uri = |rkey: &str| crate::test_util::uri("com.example.board", rkey);
+    let record = map(vec![
+        (
+            "subject",
+            map(vec![("cid", Ipld::String("c".into())), ("uri", uri("a"))]),
+        ),
+        ("board", uri("b")),
+        ("note", uri("c")),
+        (
+            "by",
+            Ipld::List(vec![map(vec![
+                ("did", Ipld::String("did:plc:b".into())),
+                ("name", Ipld::String("did:plc:c".into())),
+            ])]),
+        ),
+    ]);
+    let (backlinks, identity_links) = get_links_with_schema(schema, &record)?;
+    let mut backlinks = backlinks.into_iter().collect::<Vec<_>>();
+    backlinks.sort();
+    assert_eq!(
+        backlinks,
+        [
+            (None, "at://did:plc:a/com.example.board/b", "board".into()),
+            (
+                Some("c"),
+                "at://did:plc:a/com.example.board/a",
+                "subject".into()
+            ),
+        ]
+    );
+    assert_eq!(
+        identity_links,
+        HashSet::from([("did:plc:b", "by[].did".to_string())])
+    );
+
+    Ok(())
+}
@@ -1,5 +1,7 @@
 pub mod common;
 
 pub mod carslice;
+pub mod filter;
+pub mod lexicon;
 pub mod record;
 pub mod repo_car;
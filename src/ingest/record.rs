@@ -3,18 +3,264 @@ use std::collections::HashSet;
 use anyhow::Result;
 use ipld_core::ipld::Ipld;
 
+use crate::data::{at_uri::parse_at_uri, did::is_did};
+
+use super::lexicon::{Lexicons, LinkKind, RecordSchema};
+
+// records are small, but nothing stops anyone from putting a very deep or very wide tree
+// in one, so extraction gives up past these
+pub const MAX_LINK_DEPTH: usize = 32;
+pub const MAX_LINKS_PER_RECORD: usize = 256;
+
+/// every record the record links to, as `(cid, uri, path)`: StrongRefs (maps with a `cid` and
+/// a `uri`) anywhere in the tree, and bare at-uri strings, which don't come with a cid
 #[inline(always)]
-pub fn get_backlinks(record: &Ipld) -> Result<HashSet<(&str, &str)>> {
-    let mut backlinks = HashSet::<(&str, &str)>::new();
-    for child in record.iter() {
-        // a StrongRef is an Ipld::Map with "cid" and "uri"
-        let Ipld::Map(map) = child else {
-            continue;
-        };
-        if let (Some(Ipld::String(cid)), Some(Ipld::String(uri))) = (map.get("cid"), map.get("uri"))
-        {
-            backlinks.insert((cid, uri));
+pub fn get_backlinks(record: &Ipld) -> Result<Backlinks<'_>> {
+    let mut backlinks = Backlinks::new();
+    visit(record, &mut String::new(), 0, &mut |node, path| {
+        if backlinks.len() >= MAX_LINKS_PER_RECORD {
+            return false;
         }
+        match node {
+            Ipld::Map(map) => match (map.get("cid"), map.get("uri")) {
+                (Some(Ipld::String(cid)), Some(Ipld::String(uri))) => {
+                    backlinks.insert((Some(cid), uri, path.to_string()));
+                    // so that its uri doesn't get picked up again as a bare one
+                    false
+                }
+                _ => true,
+            },
+            // only ones that point at a record, since that's all a backlink target can be
+            Ipld::String(uri) if uri.starts_with("at://") && parse_at_uri(uri).is_ok() => {
+                backlinks.insert((None, uri, path.to_string()));
+                false
+            }
+            _ => true,
+        }
+    });
+    if backlinks.len() >= MAX_LINKS_PER_RECORD {
+        tracing::debug!("only kept the first {MAX_LINKS_PER_RECORD} links of a record");
     }
     Ok(backlinks)
 }
+
+/// every did that the record links to directly, i.e. that is the value of one of its own
+/// fields (e.g. a follow's `subject`), with its path. dids deeper in the record are as likely
+/// to be something else entirely (like the author of a quoted post), so they're left to lexicons
+pub fn get_identity_links(record: &Ipld) -> Result<IdentityLinks<'_>> {
+    let mut links = IdentityLinks::new();
+    let Ipld::Map(map) = record else {
+        return Ok(links);
+    };
+    for (key, value) in map {
+        if links.len() >= MAX_LINKS_PER_RECORD {
+            tracing::debug!(
+                "only kept the first {MAX_LINKS_PER_RECORD} identity links of a record"
+            );
+            break;
+        }
+        if let Ipld::String(did) = value {
+            if is_did(did) {
+                links.insert((did, key.clone()));
+            }
+        }
+    }
+    Ok(links)
+}
+
+pub type Backlinks<'a> = HashSet<(Option<&'a str>, &'a str, String)>;
+pub type IdentityLinks<'a> = HashSet<(&'a str, String)>;
+
+/// the backlinks and identity links in a record of `collection`, going by its lexicon if we
+/// have one, and by get_backlinks and get_identity_links if we don't
+pub fn get_links<'a>(
+    lexicons: &Lexicons,
+    collection: &str,
+    record: &'a Ipld,
+) -> Result<(Backlinks<'a>, IdentityLinks<'a>)> {
+    match lexicons.get(collection) {
+        Some(schema) => get_links_with_schema(schema, record),
+        None => Ok((get_backlinks(record)?, get_identity_links(record)?)),
+    }
+}
+
+/// like get_links, but only looks where `schema` says there are links
+pub fn get_links_with_schema<'a>(
+    schema: &RecordSchema,
+    record: &'a Ipld,
+) -> Result<(Backlinks<'a>, IdentityLinks<'a>)> {
+    let mut backlinks = Backlinks::new();
+    let mut identity_links = IdentityLinks::new();
+    visit(record, &mut String::new(), 0, &mut |node, path| {
+        if backlinks.len() + identity_links.len() >= MAX_LINKS_PER_RECORD {
+            return false;
+        }
+        for kind in schema.fields.get(path).into_iter().flatten() {
+            match (kind, node) {
+                (LinkKind::StrongRef, Ipld::Map(map)) => {
+                    if let (Some(Ipld::String(cid)), Some(Ipld::String(uri))) =
+                        (map.get("cid"), map.get("uri"))
+                    {
+                        backlinks.insert((Some(cid), uri, path.to_string()));
+                    }
+                }
+                (LinkKind::AtUri, Ipld::String(uri)) if parse_at_uri(uri).is_ok() => {
+                    backlinks.insert((None, uri, path.to_string()));
+                }
+                // the lexicon can't tell a did and a handle in an at-identifier apart
+                (LinkKind::Did, Ipld::String(did)) if is_did(did) => {
+                    identity_links.insert((did, path.to_string()));
+                }
+                _ => {}
+            }
+        }
+        schema.prefixes.contains(path)
+    });
+    Ok((backlinks, identity_links))
+}
+
+// calls `f` with every node in the record and where it is: map keys joined by dots, with `[]`
+// for list items (e.g. `embed.images[].image`), and goes on into its children if `f` returns
+// true. `path` is restored to what it was before returning
+fn visit<'a>(
+    node: &'a Ipld,
+    path: &mut String,
+    depth: usize,
+    f: &mut impl FnMut(&'a Ipld, &str) -> bool,
+) {
+    if depth >= MAX_LINK_DEPTH || !f(node, path) {
+        return;
+    }
+    let len = path.len();
+    match node {
+        Ipld::Map(map) => {
+            for (key, child) in map {
+                if !path.is_empty() {
+                    path.push('.');
+                }
+                path.push_str(key);
+                visit(child, path, depth + 1, f);
+                path.truncate(len);
+            }
+        }
+        Ipld::List(list) => {
+            path.push_str("[]");
+            for child in list {
+                visit(child, path, depth + 1, f);
+            }
+            path.truncate(len);
+        }
+        _ => {}
+    }
+}
+
+#[test]
+fn test_backlink_paths() -> Result<()> {
+    use crate::test_util::{map, uri};
+    use std::collections::BTreeMap;
+
+    let strong_ref = |cid: &str| {
+        map(vec![
+            ("cid", Ipld::String(cid.into())),
+            ("uri", uri("app.bsky.feed.post", cid)),
+        ])
+    };
+    let record = map(vec![
+        ("$type", Ipld::String("app.bsky.feed.post".into())),
+        ("subject", Ipld::String("did:plc:b".into())),
+        (
+            "reply",
+            map(vec![
+                ("root", strong_ref("root")),
+                ("parent", strong_ref("parent")),
+            ]),
+        ),
+        ("embed", map(vec![("record", strong_ref("quote"))])),
+        ("items", Ipld::List(vec![strong_ref("item")])),
+        (
+            "quoted",
+            map(vec![("author", Ipld::String("did:plc:c".into()))]),
+        ),
+        ("tags", Ipld::List(vec![Ipld::String("did:plc:d".into())])),
+    ]);
+
+    let mut paths = get_backlinks(&record)?
+        .into_iter()
+        .map(|(cid, _, path)| (cid.unwrap(), path))
+        .collect::<Vec<_>>();
+    paths.sort();
+    assert_eq!(
+        paths,
+        [
+            ("item", "items[]".into()),
+            ("parent", "reply.parent".into()),
+            ("quote", "embed.record".into()),
+            ("root", "reply.root".into()),
+        ]
+    );
+    // the dids inside the strong refs' uris don't count, since those link to records,
+    // and neither do ones further down or in lists
+    assert_eq!(
+        get_identity_links(&record)?,
+        HashSet::from([("did:plc:b", "subject".to_string())])
+    );
+    let wide = Ipld::Map(BTreeMap::from_iter(
+        (0..MAX_LINKS_PER_RECORD * 2)
+            .map(|i| (i.to_string(), Ipld::String(format!("did:plc:{i}")))),
+    ));
+    assert_eq!(get_identity_links(&wide)?.len(), MAX_LINKS_PER_RECORD);
+    Ok(())
+}
+
+#[test]
+fn test_bare_at_uris() -> Result<()> {
+    use crate::test_util::map;
+
+    let uri = |rkey: &str| crate::test_util::uri("app.bsky.graph.list", rkey);
+    let record = map(vec![
+        ("list", uri("list")),
+        ("feeds", Ipld::List(vec![map(vec![("uri", uri("feed"))])])),
+        ("hiddenReplies", Ipld::List(vec![uri("reply")])),
+        // not a record, so not something we can link back from
+        ("profile", Ipld::String("at://did:plc:a".into())),
+    ]);
+
+    let mut links = get_backlinks(&record)?.into_iter().collect::<Vec<_>>();
+    links.sort();
+    assert_eq!(
+        links,
+        [
+            (
+                None,
+                "at://did:plc:a/app.bsky.graph.list/feed",
+                "feeds[].uri".into()
+            ),
+            (
+                None,
+                "at://did:plc:a/app.bsky.graph.list/list",
+                "list".into()
+            ),
+            (
+                None,
+                "at://did:plc:a/app.bsky.graph.list/reply",
+                "hiddenReplies[]".into()
+            ),
+        ]
+    );
+
+    // anything past the limits gets left out
+    let mut deep = uri("deep");
+    for _ in 0..MAX_LINK_DEPTH - 1 {
+        deep = Ipld::List(vec![deep]);
+    }
+    assert_eq!(get_backlinks(&deep)?.len(), 1);
+    let deep = Ipld::List(vec![deep]);
+    assert!(get_backlinks(&deep)?.is_empty());
+    let wide = Ipld::List(
+        (0..MAX_LINKS_PER_RECORD * 2)
+            .map(|i| uri(&i.to_string()))
+            .collect(),
+    );
+    assert_eq!(get_backlinks(&wide)?.len(), MAX_LINKS_PER_RECORD);
+    Ok(())
+}
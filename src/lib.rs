@@ -1,8 +1,10 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, sync::Arc};
 
 use anyhow::Result;
 use counter::MonotonicCounter;
 use db::{setup_db, DbCaches, DbConnection};
+use ingest::{filter::CollectionFilter, lexicon::Lexicons};
+use storage::deletion::DeletionStores;
 use uuid::Uuid;
 use zplc_client::ZplcDirectResolver;
 
@@ -20,16 +22,46 @@ pub mod storage;
 pub mod tid;
 pub mod zplc_client;
 
+#[cfg(test)]
+mod test_util;
+
 pub struct AppConfig {
     pub zplc_path: String,
     pub data_dir: PathBuf,
+    // keep a hash of the cid that each strong ref points at (see data::cid::CidHash)
+    pub store_cids: bool,
+    // where lexicons get read from (LEXICON_DIRS, separated by ':'). the vendored atrium ones
+    // come first, so that third-party lexicons dropped in later directories can override them.
+    // only the binaries that ingest records read them (see AppContext::load_lexicons)
+    pub lexicon_dirs: Vec<PathBuf>,
+    // which collections backlinks get kept from and to
+    // (SOURCE_COLLECTIONS_ALLOW/_DENY and TARGET_COLLECTIONS_ALLOW/_DENY)
+    pub source_collections: CollectionFilter,
+    pub target_collections: CollectionFilter,
 }
 
 pub fn get_app_config() -> Result<AppConfig> {
     // TODO: read from environment variables or whatever
+    // relative to the checkout rather than wherever we happen to be started from
+    let mut lexicon_dirs = vec![PathBuf::from(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/vendor/atrium/lexicons"
+    ))];
+    if let Ok(dirs) = std::env::var("LEXICON_DIRS") {
+        lexicon_dirs.extend(
+            dirs.split(':')
+                .filter(|dir| !dir.is_empty())
+                .map(PathBuf::from),
+        );
+    }
+
     Ok(AppConfig {
         zplc_path: "../zplc-server/data/ids.db".into(),
         data_dir: "./data".into(),
+        store_cids: std::env::var("STORE_CIDS").is_ok_and(|v| v == "1"),
+        lexicon_dirs,
+        source_collections: CollectionFilter::from_env("SOURCE_COLLECTIONS")?,
+        target_collections: CollectionFilter::from_env("TARGET_COLLECTIONS")?,
     })
 }
 
@@ -40,7 +72,12 @@ pub struct AppContext {
     pub db_path: PathBuf,
     pub db: DbConnection,
     pub caches: DbCaches,
+    pub deletion_stores: DeletionStores,
     pub backfill_db: Option<rusqlite::Connection>,
+    pub store_cids: bool,
+    pub lexicons: Arc<Lexicons>,
+    pub source_collections: CollectionFilter,
+    pub target_collections: CollectionFilter,
 
     pub zplc_direct_resolver: ZplcDirectResolver,
     pub backlinks_counter: MonotonicCounter,
@@ -60,7 +97,12 @@ impl AppContext {
             db_path,
             db,
             caches: DbCaches::default(),
+            deletion_stores: DeletionStores::default(),
             backfill_db: None,
+            store_cids: cfg.store_cids,
+            lexicons: Arc::default(),
+            source_collections: cfg.source_collections.clone(),
+            target_collections: cfg.target_collections.clone(),
 
             zplc_direct_resolver: ZplcDirectResolver {
                 conn: rusqlite::Connection::open(cfg.zplc_path.clone())?,
@@ -69,6 +111,19 @@ impl AppContext {
         })
     }
 
+    /// reads the lexicons in `cfg.lexicon_dirs`. until this is called, every record
+    /// gets its links found by ingest::record's heuristics
+    pub fn load_lexicons(&mut self, cfg: &AppConfig) -> Result<()> {
+        let lexicons = Lexicons::load(&cfg.lexicon_dirs)?;
+        if lexicons.is_empty() {
+            tracing::warn!(dirs = ?cfg.lexicon_dirs, "no record lexicons found, is the atrium submodule checked out?");
+        } else {
+            tracing::debug!(records = lexicons.len(), "loaded lexicons");
+        }
+        self.lexicons = Arc::new(lexicons);
+        Ok(())
+    }
+
     pub fn connect_to_db(&self) -> Result<DbConnection> {
         let conn = DbConnection::open(&self.db_path)?;
         setup_db(&conn)?;
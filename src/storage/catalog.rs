@@ -0,0 +1,299 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+};
+
+use anyhow::Result;
+
+use crate::{
+    data::{cid::CidHash, record::RecordId},
+    db::DbConnection,
+};
+
+use super::{
+    compacted::CompactedStorageReader,
+    live::{LiveStorageReader, IDENTITY_DIR},
+    live_guards::LiveReadHandle,
+    store::{BacklinkStore, StoreType},
+};
+
+// keeps every data store open for as long as it is listed in `data_stores`, so that
+// long-running readers (i.e. the API) don't have to reopen all of them for every lookup.
+//
+// sqlite bumps `PRAGMA data_version` whenever another connection commits, which is a cheap
+// way of noticing that rollover, compaction, merges or cleanup may have changed the list.
+// when it does, a new snapshot is built (reusing the stores that are still there) and swapped
+// in; requests that are already running keep using the snapshot they started with.
+
+enum OpenStore {
+    // live readers keep a cache and refresh their hash index, so they need `&mut`
+    Live(Mutex<LiveStores>),
+    Compacted {
+        reader: CompactedStorageReader,
+        // stores compacted before identity links were kept don't have any
+        identity: Option<CompactedStorageReader>,
+    },
+}
+
+struct LiveStores {
+    handle: LiveReadHandle,
+    // a store that was created before identity links were kept only gets an identity dir
+    // once a writer opens it again, so this is retried until there is one
+    identity: Option<LiveStorageReader>,
+}
+
+impl LiveStores {
+    fn identity(&mut self) -> Result<Option<&mut LiveStorageReader>> {
+        if self.identity.is_none() {
+            let dir = self.handle.dir().join(IDENTITY_DIR);
+            if dir.exists() {
+                self.identity = Some(LiveStorageReader::new(dir)?);
+            }
+        }
+        Ok(self.identity.as_mut())
+    }
+}
+
+struct CatalogStore {
+    id: u64,
+    name: String,
+    store_type: String,
+    store: OpenStore,
+}
+
+/// the stores that were listed in `data_stores` at some point, oldest first
+pub struct CatalogSnapshot {
+    stores: Vec<Arc<CatalogStore>>,
+}
+
+impl CatalogSnapshot {
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.stores.iter().map(|store| store.name.as_str())
+    }
+
+    pub fn read_backlinks(
+        &self,
+        target: &RecordId,
+        sources: &mut BTreeSet<RecordId>,
+    ) -> Result<()> {
+        for store in &self.stores {
+            match &store.store {
+                OpenStore::Live(live) => {
+                    let mut live = live.lock().unwrap();
+                    BacklinkStore::read_backlinks(&mut live.handle, target, sources)?;
+                }
+                OpenStore::Compacted { reader, .. } => reader.read_backlinks(target, sources)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// like `read_backlinks`, but also adds the cid of every source that has one to `cids`
+    pub fn read_backlinks_with_cids(
+        &self,
+        target: &RecordId,
+        sources: &mut BTreeSet<RecordId>,
+        cids: &mut BTreeMap<RecordId, CidHash>,
+    ) -> Result<()> {
+        for store in &self.stores {
+            match &store.store {
+                OpenStore::Live(live) => {
+                    let mut live = live.lock().unwrap();
+                    live.handle
+                        .reader
+                        .read_backlinks_with_cids(target, sources, cids)?;
+                }
+                OpenStore::Compacted { reader, .. } => {
+                    reader.read_backlinks_with_cids(target, sources, cids)?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// adds every record that links to the identity `did` itself (e.g. follows) to `sources`
+    pub fn read_identity_links(&self, did: u64, sources: &mut BTreeSet<RecordId>) -> Result<()> {
+        let target = RecordId::identity(did);
+        for store in &self.stores {
+            match &store.store {
+                OpenStore::Live(live) => {
+                    let mut live = live.lock().unwrap();
+                    if let Some(identity) = live.identity()? {
+                        identity.read_backlinks(&target, sources)?;
+                    }
+                }
+                OpenStore::Compacted {
+                    identity: Some(identity),
+                    ..
+                } => identity.read_backlinks(&target, sources)?,
+                OpenStore::Compacted { identity: None, .. } => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct StoreCatalog {
+    data_dir: PathBuf,
+    // with the data_version that `current` was built from
+    db: Mutex<(DbConnection, Option<i64>)>,
+    current: RwLock<Arc<CatalogSnapshot>>,
+}
+
+impl StoreCatalog {
+    /// `db` should be a connection of its own, since writes through it don't bump data_version
+    pub fn open(data_dir: &Path, db: DbConnection) -> Result<Self> {
+        let catalog = Self {
+            data_dir: data_dir.to_path_buf(),
+            db: Mutex::new((db, None)),
+            current: RwLock::new(Arc::new(CatalogSnapshot { stores: Vec::new() })),
+        };
+        catalog.snapshot()?;
+        Ok(catalog)
+    }
+
+    /// the current stores, after picking up any changes to `data_stores`
+    pub fn snapshot(&self) -> Result<Arc<CatalogSnapshot>> {
+        let mut db = self.db.lock().unwrap();
+        let data_version: i64 =
+            db.0.pragma_query_value(None, "data_version", |row| row.get(0))?;
+        if db.1 != Some(data_version) {
+            let complete = self.refresh(&db.0)?;
+            // stores that failed to open get another try next time
+            db.1 = complete.then_some(data_version);
+        }
+        drop(db);
+
+        Ok(Arc::clone(&self.current.read().unwrap()))
+    }
+
+    // returns whether every store could be opened
+    fn refresh(&self, db: &DbConnection) -> Result<bool> {
+        let mut statement =
+            db.prepare_cached("SELECT id, name, type FROM data_stores ORDER BY id ASC")?;
+        let rows = statement
+            .query_map((), |row| {
+                Ok((
+                    row.get::<_, u64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let old = Arc::clone(&self.current.read().unwrap());
+        let mut stores = Vec::with_capacity(rows.len());
+        let mut complete = true;
+        for (id, name, store_type) in rows {
+            let existing = old.stores.iter().find(|store| {
+                store.id == id && store.name == name && store.store_type == store_type
+            });
+            if let Some(existing) = existing {
+                stores.push(Arc::clone(existing));
+                continue;
+            }
+
+            let store = StoreType::parse(&store_type).and_then(|kind| match kind {
+                StoreType::Live => open_live(&self.data_dir, &name),
+                StoreType::Compacted => open_compacted(&kind.dir(&self.data_dir, &name)),
+            });
+            match store {
+                Ok(store) => stores.push(Arc::new(CatalogStore {
+                    id,
+                    name,
+                    store_type,
+                    store,
+                })),
+                Err(e) => {
+                    tracing::warn!(?name, "could not open data store: {e:?}");
+                    complete = false;
+                }
+            }
+        }
+
+        *self.current.write().unwrap() = Arc::new(CatalogSnapshot { stores });
+        Ok(complete)
+    }
+}
+
+fn open_live(data_dir: &Path, name: &str) -> Result<OpenStore> {
+    let mut live = LiveStores {
+        handle: LiveReadHandle::open(data_dir, name)?,
+        identity: None,
+    };
+    live.identity()?;
+    Ok(OpenStore::Live(Mutex::new(live)))
+}
+
+fn open_compacted(dir: &Path) -> Result<OpenStore> {
+    let reader = CompactedStorageReader::new(dir)?;
+    let identity = match dir.join(IDENTITY_DIR).exists() {
+        true => Some(CompactedStorageReader::new(dir.join(IDENTITY_DIR))?),
+        false => None,
+    };
+    Ok(OpenStore::Compacted { reader, identity })
+}
+
+#[test]
+fn test_catalog() -> Result<()> {
+    use super::{compacted::CompactedStorageWriter, live::LiveStorageWriter};
+
+    let data_dir = crate::test_util::TempDir::new();
+    std::fs::create_dir_all(&data_dir)?;
+    let db = rusqlite::Connection::open(data_dir.join("db"))?;
+    crate::db::setup_db(&db)?;
+    let (target, source) = (RecordId::new(1, 1, 1), RecordId::new(2, 2, 2));
+
+    let mut live = LiveStorageWriter::new(data_dir.join("live").join("a"))?;
+    live.log_backlink(&target, &source)?;
+    live.log_identity_link(target.did, &source)?;
+    db.execute(
+        "INSERT INTO data_stores (name, type) VALUES ('a', 'live')",
+        (),
+    )?;
+
+    let catalog_db = rusqlite::Connection::open(data_dir.join("db"))?;
+    let catalog = StoreCatalog::open(&data_dir, catalog_db)?;
+    let before = catalog.snapshot()?;
+    assert!(before.names().eq(["a"]));
+    assert!(Arc::ptr_eq(&before, &catalog.snapshot()?));
+
+    // 'a' gets compacted, and a new live store with nothing in it yet shows up
+    let mut compacted = CompactedStorageWriter::new(data_dir.join("compacted").join("a"))?;
+    compacted.log_backlinks(&target, &BTreeSet::from([source]))?;
+    compacted.finish()?;
+    db.execute(
+        "UPDATE data_stores SET type = 'compacted' WHERE name = 'a'",
+        (),
+    )?;
+    db.execute(
+        "INSERT INTO data_stores (name, type) VALUES ('b', 'live')",
+        (),
+    )?;
+
+    // 'b' can't be opened until its writer has created it
+    let after = catalog.snapshot()?;
+    assert!(after.names().eq(["a"]));
+    drop(LiveStorageWriter::new(data_dir.join("live").join("b"))?);
+    let after = catalog.snapshot()?;
+    assert!(after.names().eq(["a", "b"]));
+
+    // the old snapshot still works, even once the live store is gone
+    std::fs::remove_dir_all(data_dir.join("live").join("a"))?;
+    for snapshot in [&before, &after] {
+        let mut sources = BTreeSet::new();
+        snapshot.read_backlinks(&target, &mut sources)?;
+        assert_eq!(sources, BTreeSet::from([source]));
+    }
+    // 'a' was compacted without its identity links, and 'b' doesn't have any
+    let mut sources = BTreeSet::new();
+    before.read_identity_links(target.did, &mut sources)?;
+    assert_eq!(sources, BTreeSet::from([source]));
+    let mut sources = BTreeSet::new();
+    after.read_identity_links(target.did, &mut sources)?;
+    assert!(sources.is_empty());
+
+    drop((before, after, catalog));
+    Ok(())
+}
@@ -3,143 +3,611 @@
 
 use std::{
     cmp::Ordering,
-    collections::BTreeSet,
+    collections::{BTreeMap, BTreeSet},
     fs::File,
-    io::{Read, Seek, SeekFrom, Write},
+    io::{Read, Seek, Write},
     mem::size_of,
     os::fd::AsRawFd,
-    path::Path,
+    path::{Path, PathBuf},
 };
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use memmap2::Mmap;
 use nix::fcntl::{flock, FlockArg};
 use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
 
 use crate::{
-    data::{record::RecordId, Padding},
+    data::{
+        cid::CidHash,
+        record::{
+            RecordId, RECORD_FLAG_CIDS, RECORD_FLAG_COMPRESSED_BLOCK, RECORD_FLAG_LINK_PATHS,
+        },
+        Padding,
+    },
     storage::pwrite_all,
 };
 
-use super::pread_all;
+use super::{
+    compacted_bloom::{CompactedBloomFilter, BLOOM_FILE},
+    pread_all, FormatInfo,
+};
 
 #[derive(Debug, Clone, Copy, KnownLayout, Immutable, IntoBytes, FromBytes)]
+#[repr(C)]
 pub struct RecordIndexHeader {
     pub num_entries: u64,
-    _pad: Padding<24>,
+    pub format: FormatInfo,
+    _pad: Padding<8>,
 }
 pub const INDEX_HEADER_SIZE: usize = size_of::<RecordIndexHeader>();
 const _: [(); 32] = [(); INDEX_HEADER_SIZE];
 
+pub const COMPACTED_MAGIC: [u8; 4] = *b"BSCP";
+// 0: from before headers had a format. collections are stored as-is, there are no tombstones
+// 1: collections are shifted left by one to make room for the tombstone bit
+pub const COMPACTED_VERSION: u32 = 1;
+// index entries have 64-bit positions (see NarrowRecordIndexEntry)
+pub const COMPACTED_FLAG_WIDE_POSITIONS: u64 = 1 << 0;
+// some link blocks are compressed (see RECORD_FLAG_COMPRESSED_BLOCK)
+pub const COMPACTED_FLAG_COMPRESSED_BLOCKS: u64 = 1 << 1;
+// some link blocks store paths (see RECORD_FLAG_LINK_PATHS)
+pub const COMPACTED_FLAG_LINK_PATHS: u64 = 1 << 2;
+// some link blocks store cids (see RECORD_FLAG_CIDS)
+pub const COMPACTED_FLAG_CIDS: u64 = 1 << 3;
+pub const COMPACTED_FLAGS: u64 = COMPACTED_FLAG_WIDE_POSITIONS
+    | COMPACTED_FLAG_COMPRESSED_BLOCKS
+    | COMPACTED_FLAG_LINK_PATHS
+    | COMPACTED_FLAG_CIDS;
+
+// this is also the layout of index entries in stores with COMPACTED_FLAG_WIDE_POSITIONS
 #[derive(Debug, Clone, Copy, KnownLayout, Immutable, IntoBytes, FromBytes)]
 #[repr(C, packed)]
 pub struct RecordIndexEntry {
     pub target: RecordId,
     pub count: u32,
     // byte index in links file divided by POS_ALIGN (we'll write padding)
+    pub position: u64,
+}
+pub const WIDE_INDEX_ENTRY_SIZE: usize = size_of::<RecordIndexEntry>();
+// size assertion
+const _: [(); 36] = [(); WIDE_INDEX_ENTRY_SIZE];
+
+// index entries in every other store. positions only go up to 2^32 × POS_ALIGN (128 GiB)
+#[derive(Debug, Clone, Copy, KnownLayout, Immutable, IntoBytes, FromBytes)]
+#[repr(C, packed)]
+pub struct NarrowRecordIndexEntry {
+    pub target: RecordId,
+    pub count: u32,
     pub position: u32,
 }
-pub const INDEX_ENTRY_SIZE: usize = size_of::<RecordIndexEntry>();
+pub const NARROW_INDEX_ENTRY_SIZE: usize = size_of::<NarrowRecordIndexEntry>();
 // size assertion
-const _: [(); 32] = [(); INDEX_ENTRY_SIZE];
+const _: [(); 32] = [(); NARROW_INDEX_ENTRY_SIZE];
 const POS_ALIGN: u64 = 32;
+pub const NARROW_LINKS_LIMIT: u64 = (u32::MAX as u64 + 1) * POS_ALIGN;
+
+fn index_entry_size(wide: bool) -> usize {
+    match wide {
+        true => WIDE_INDEX_ENTRY_SIZE,
+        false => NARROW_INDEX_ENTRY_SIZE,
+    }
+}
+
+fn decode_index_entry(wide: bool, buf: &[u8]) -> RecordIndexEntry {
+    if wide {
+        return RecordIndexEntry::read_from_bytes(buf).unwrap();
+    }
+    let entry = NarrowRecordIndexEntry::read_from_bytes(buf).unwrap();
+    RecordIndexEntry {
+        target: entry.target,
+        count: entry.count,
+        position: entry.position as u64,
+    }
+}
 
 // to store an array of `count` BacklinkEntry structures:
 //   - order by rkey
 //   - count × u64 rkey
-//   - count × leb128 (u32 as u31) collection
+//   - count × leb128 (u32 as u31, shifted left by one) collection
 //   - count × leb128 (u64) did
-// if 32nd bit of collection is set, this record has been deleted and should be skipped.
+//   - count × leb128 (u32) path, only if the index entry's target has RECORD_FLAG_LINK_PATHS
+//     (blocks where none of the sources have a path leave it out)
+//   - count × u64 CidHash, only if the index entry's target has RECORD_FLAG_CIDS
+//     (likewise, and sources without one get 0)
+// if the low bit of the collection is set, this record has been deleted and should be skipped.
+// the low bit lives in the first byte of the varint, so we can set it in place without
+// changing the encoded length.
 // we store the rkeys uncompressed and contiguously so that we can binary search
 // for a specific RecordId, so that deletion-marking in compacted stores can be fast
+const COLLECTION_TOMBSTONE: u32 = 1;
+
+// targets with a lot of sources get their block compressed instead, if that makes it smaller.
+// their index entry's target has RECORD_FLAG_COMPRESSED_BLOCK set, and the block is:
+//   - u32 length of the compressed payload
+//   - ceil(count / 8) bytes of tombstone bits, one per source, left uncompressed so they can
+//     still be set in place
+//   - zstd-compressed payload of:
+//     - leb128 (u64) first rkey, then count - 1 × leb128 (u64) delta from the previous one
+//     - runs of leb128 (u32, unshifted) collection + leb128 (u32) number of sources in the run
+//     - count × leb128 (u64) did
+//     - runs of leb128 (u32) path + leb128 (u32) number of sources in the run, only if the
+//       index entry's target has RECORD_FLAG_LINK_PATHS
+//     - count × u64 CidHash, only if the index entry's target has RECORD_FLAG_CIDS
+const COMPRESSED_BLOCK_MIN_COUNT: usize = 64;
+const COMPRESSION_LEVEL: i32 = 3;
+
+fn is_compressed(entry: &RecordIndexEntry) -> bool {
+    entry.target._flags.0 & RECORD_FLAG_COMPRESSED_BLOCK != 0
+}
+
+fn has_paths(entry: &RecordIndexEntry) -> bool {
+    entry.target._flags.0 & RECORD_FLAG_LINK_PATHS != 0
+}
+
+fn has_cids(entry: &RecordIndexEntry) -> bool {
+    entry.target._flags.0 & RECORD_FLAG_CIDS != 0
+}
+
+fn read_cids(reader: &mut &[u8], count: usize) -> Result<Vec<CidHash>> {
+    let mut cids = vec![0 as CidHash; count];
+    reader.read_exact(cids.as_mut_bytes())?;
+    Ok(cids)
+}
+
+// returns the collection and whether it has been tombstoned
+fn decode_collection(version: u32, collection: u32) -> (u32, bool) {
+    match version {
+        0 => (collection, false),
+        _ => (collection >> 1, collection & COLLECTION_TOMBSTONE != 0),
+    }
+}
+
+fn read_header(index: &File) -> Result<RecordIndexHeader> {
+    let mut header_buf = [0u8; INDEX_HEADER_SIZE];
+    pread_all(index, &mut header_buf, 0)?;
+    Ok(zerocopy::transmute!(header_buf))
+}
+
+struct LinkBlock {
+    rkeys: Vec<u64>,
+    // still shifted, with the tombstone bit
+    collections: Vec<u32>,
+    dids: Vec<u64>,
+    // empty if the block doesn't store paths
+    paths: Vec<u32>,
+    // empty if the block doesn't store cids
+    cids: Vec<CidHash>,
+    // bytes taken up in links.dat, not counting padding
+    len: u64,
+}
+
+// everything from the start of `entry`'s block to the end of links.dat
+fn link_block_bytes<'a>(links: &'a [u8], entry: &RecordIndexEntry) -> Result<&'a [u8]> {
+    usize::try_from(entry.position * POS_ALIGN)
+        .ok()
+        .and_then(|start| links.get(start..))
+        .context("block starts past the end of links.dat")
+}
+
+fn read_link_block(links: &[u8], entry: &RecordIndexEntry) -> Result<LinkBlock> {
+    if is_compressed(entry) {
+        return read_compressed_link_block(links, entry);
+    }
+    let count = entry.count as usize;
+
+    let mut reader = link_block_bytes(links, entry)?;
+
+    let mut rkeys = vec![0u64; count];
+    reader.read_exact(rkeys.as_mut_bytes())?;
+    let mut collections = Vec::<u32>::with_capacity(count);
+    for _ in 0..count {
+        collections.push(unsigned_varint::io::read_u32(&mut reader)?);
+    }
+    let mut dids = Vec::<u64>::with_capacity(count);
+    for _ in 0..count {
+        dids.push(unsigned_varint::io::read_u64(&mut reader)?);
+    }
+    let mut paths = Vec::<u32>::new();
+    if has_paths(entry) {
+        for _ in 0..count {
+            paths.push(unsigned_varint::io::read_u32(&mut reader)?);
+        }
+    }
+    let cids = match has_cids(entry) {
+        true => read_cids(&mut reader, count)?,
+        false => Vec::new(),
+    };
+
+    let len = count as u64 * 8
+        + collections.iter().map(|c| varint_len_u32(*c)).sum::<u64>()
+        + dids.iter().map(|d| varint_len_u64(*d)).sum::<u64>()
+        + paths.iter().map(|p| varint_len_u32(*p)).sum::<u64>()
+        + (cids.len() * size_of::<CidHash>()) as u64;
+    Ok(LinkBlock {
+        rkeys,
+        collections,
+        dids,
+        paths,
+        cids,
+        len,
+    })
+}
+
+fn read_compressed_link_block(links: &[u8], entry: &RecordIndexEntry) -> Result<LinkBlock> {
+    let count = entry.count as usize;
+
+    let mut block = link_block_bytes(links, entry)?;
+    let mut len_buf = [0u8; 4];
+    block.read_exact(&mut len_buf)?;
+    let compressed_len = u32::from_le_bytes(len_buf) as usize;
+    let buf = block
+        .get(..count.div_ceil(8) + compressed_len)
+        .context("block runs past the end of links.dat")?;
+    let (tombstones, compressed) = buf.split_at(count.div_ceil(8));
+    let payload = zstd::stream::decode_all(compressed)?;
+    let mut reader = payload.as_slice();
+
+    let mut rkeys = Vec::<u64>::with_capacity(count);
+    let mut rkey = 0u64;
+    for _ in 0..count {
+        rkey = rkey.wrapping_add(unsigned_varint::io::read_u64(&mut reader)?);
+        rkeys.push(rkey);
+    }
+    let collections = read_runs(&mut reader, count)?
+        .into_iter()
+        .enumerate()
+        .map(|(i, collection)| {
+            let tombstone = (tombstones[i / 8] >> (i % 8)) as u32 & COLLECTION_TOMBSTONE;
+            collection << 1 | tombstone
+        })
+        .collect();
+    let mut dids = Vec::<u64>::with_capacity(count);
+    for _ in 0..count {
+        dids.push(unsigned_varint::io::read_u64(&mut reader)?);
+    }
+    let paths = match has_paths(entry) {
+        true => read_runs(&mut reader, count)?,
+        false => Vec::new(),
+    };
+    let cids = match has_cids(entry) {
+        true => read_cids(&mut reader, count)?,
+        false => Vec::new(),
+    };
+
+    Ok(LinkBlock {
+        rkeys,
+        collections,
+        dids,
+        paths,
+        cids,
+        len: (len_buf.len() + buf.len()) as u64,
+    })
+}
+
+// reads (value, run length) pairs until there are `count` values
+fn read_runs(reader: &mut &[u8], count: usize) -> Result<Vec<u32>> {
+    let mut values = Vec::<u32>::with_capacity(count);
+    while values.len() < count {
+        let value = unsigned_varint::io::read_u32(&mut *reader)?;
+        let run = unsigned_varint::io::read_u32(&mut *reader)? as usize;
+        if run == 0 || values.len() + run > count {
+            anyhow::bail!("run of {run} doesn't fit in a block of {count}");
+        }
+        values.resize(values.len() + run, value);
+    }
+    Ok(values)
+}
+
+fn push_runs(payload: &mut Vec<u8>, values: impl Iterator<Item = u32>) {
+    let mut u32_buf = unsigned_varint::encode::u32_buffer();
+    let mut values = values.peekable();
+    while let Some(value) = values.next() {
+        let mut run = 1;
+        while values.next_if_eq(&value).is_some() {
+            run += 1;
+        }
+        payload.extend_from_slice(unsigned_varint::encode::u32(value, &mut u32_buf));
+        payload.extend_from_slice(unsigned_varint::encode::u32(run, &mut u32_buf));
+    }
+}
+
+// `cids` is either empty or has one for every source
+fn encode_link_block(sources: &BTreeSet<RecordId>, with_paths: bool, cids: &[CidHash]) -> Vec<u8> {
+    let mut block = Vec::with_capacity(sources.len() * size_of::<RecordId>());
+    for source in sources.iter() {
+        block.extend_from_slice(&source.rkey.to_le_bytes());
+    }
+    for source in sources.iter() {
+        debug_assert!(source.collection < 1 << 31, "collection id too large");
+        let mut collection_buf = unsigned_varint::encode::u32_buffer();
+        block.extend_from_slice(unsigned_varint::encode::u32(
+            source.collection << 1,
+            &mut collection_buf,
+        ));
+    }
+    for source in sources.iter() {
+        let mut did_buf = unsigned_varint::encode::u64_buffer();
+        block.extend_from_slice(unsigned_varint::encode::u64(source.did, &mut did_buf));
+    }
+    if with_paths {
+        for source in sources.iter() {
+            let mut path_buf = unsigned_varint::encode::u32_buffer();
+            block.extend_from_slice(unsigned_varint::encode::u32(source.path(), &mut path_buf));
+        }
+    }
+    block.extend_from_slice(cids.as_bytes());
+    block
+}
+
+fn encode_compressed_link_block(
+    sources: &BTreeSet<RecordId>,
+    with_paths: bool,
+    cids: &[CidHash],
+) -> Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    let mut u64_buf = unsigned_varint::encode::u64_buffer();
+
+    let mut last_rkey = 0;
+    for source in sources.iter() {
+        payload.extend_from_slice(unsigned_varint::encode::u64(
+            source.rkey - last_rkey,
+            &mut u64_buf,
+        ));
+        last_rkey = source.rkey;
+    }
+    push_runs(&mut payload, sources.iter().map(|s| s.collection));
+    for source in sources.iter() {
+        payload.extend_from_slice(unsigned_varint::encode::u64(source.did, &mut u64_buf));
+    }
+    if with_paths {
+        push_runs(&mut payload, sources.iter().map(|s| s.path()));
+    }
+    payload.extend_from_slice(cids.as_bytes());
+
+    let compressed = zstd::bulk::compress(&payload, COMPRESSION_LEVEL)?;
+    let mut block = Vec::with_capacity(4 + sources.len().div_ceil(8) + compressed.len());
+    block.extend_from_slice(&u32::try_from(compressed.len())?.to_le_bytes());
+    block.resize(block.len() + sources.len().div_ceil(8), 0);
+    block.extend_from_slice(&compressed);
+    Ok(block)
+}
+
+// reads from `links_map`, but writes tombstones to `links` (which the map can see)
+fn mark_deleted_in_block(
+    links_map: &[u8],
+    links: &File,
+    entry: &RecordIndexEntry,
+    source: &RecordId,
+) -> Result<bool> {
+    let count = entry.count as usize;
+    let start = entry.position * POS_ALIGN;
+
+    if is_compressed(entry) {
+        let block = read_compressed_link_block(links_map, entry)?;
+        let Some(i) = (0..count).find(|&i| {
+            block.rkeys[i] == source.rkey
+                && block.collections[i] >> 1 == source.collection
+                && block.dids[i] == source.did
+        }) else {
+            return Ok(false);
+        };
+        if block.collections[i] & COLLECTION_TOMBSTONE != 0 {
+            return Ok(false);
+        }
+
+        let tombstone_pos = start as usize + 4 + i / 8;
+        let byte = [links_map[tombstone_pos] | 1 << (i % 8)];
+        pwrite_all(links, &byte, tombstone_pos)?;
+        return Ok(true);
+    }
+
+    let mut reader = link_block_bytes(links_map, entry)?;
+    let mut rkeys = vec![0u64; count];
+    reader.read_exact(rkeys.as_mut_bytes())?;
+    let rkey = source.rkey;
+    let lo = rkeys.partition_point(|r| *r < rkey);
+    let hi = lo + rkeys[lo..].partition_point(|r| *r == rkey);
+    if lo == hi {
+        return Ok(false);
+    }
+
+    // varints can't be skipped over, so we still have to walk every collection
+    // and the dids up to the end of the matching run
+    let mut pos = start + (count * 8) as u64;
+    let mut candidates = Vec::<(u64, u32)>::with_capacity(hi - lo);
+    for i in 0..count {
+        let collection = unsigned_varint::io::read_u32(&mut reader)?;
+        if (lo..hi).contains(&i) {
+            candidates.push((pos, collection));
+        }
+        pos += unsigned_varint::encode::u32(collection, &mut unsigned_varint::encode::u32_buffer())
+            .len() as u64;
+    }
+    for i in 0..hi {
+        let did = unsigned_varint::io::read_u64(&mut reader)?;
+        if i < lo {
+            continue;
+        }
+        let (collection_pos, collection) = candidates[i - lo];
+        if collection >> 1 != source.collection || did != source.did {
+            continue;
+        }
+        if collection & COLLECTION_TOMBSTONE != 0 {
+            return Ok(false);
+        }
+
+        let collection_pos = collection_pos as usize;
+        let byte = [links_map[collection_pos] | COLLECTION_TOMBSTONE as u8];
+        pwrite_all(links, &byte, collection_pos)?;
+        return Ok(true);
+    }
+
+    Ok(false)
+}
 
 pub struct CompactedStorageWriter {
+    dir: PathBuf,
     index: File,        // create, append
     links: File,        // create, append
     index_random: File, // write
+    wide: bool,
 
     last_target: Option<RecordId>,
 }
 
 impl CompactedStorageWriter {
     pub fn new(dir: impl AsRef<Path>) -> Result<Self> {
-        let _ = std::fs::create_dir_all(&dir);
+        Self::open(dir.as_ref(), false)
+    }
+
+    /// like `new`, but switches to wide positions if links.dat might grow to `max_links_size`
+    pub fn new_for_size(dir: impl AsRef<Path>, max_links_size: u64) -> Result<Self> {
+        Self::open(dir.as_ref(), max_links_size >= NARROW_LINKS_LIMIT)
+    }
+
+    // `wide` only matters for new stores, existing ones keep whatever they were created with
+    fn open(dir: &Path, wide: bool) -> Result<Self> {
+        let _ = std::fs::create_dir_all(dir);
+        // it won't know about anything we append
+        if let Err(e) = std::fs::remove_file(dir.join(BLOOM_FILE)) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                return Err(e.into());
+            }
+        }
 
         let index = File::options()
             .create(true)
             .append(true)
-            .open(dir.as_ref().join("index.dat"))?;
+            .open(dir.join("index.dat"))?;
         let links = File::options()
             .create(true)
             .append(true)
-            .open(dir.as_ref().join("links.dat"))?;
+            .open(dir.join("links.dat"))?;
         let mut index_random = File::options()
             .read(true)
             .write(true)
             .truncate(false)
-            .open(dir.as_ref().join("index.dat"))?;
+            .open(dir.join("index.dat"))?;
         {
             let mut buf = [0u8; INDEX_HEADER_SIZE];
             if pread_all(&index_random, &mut buf, 0).is_err() {
+                let flags = match wide {
+                    true => COMPACTED_FLAG_WIDE_POSITIONS,
+                    false => 0,
+                };
                 let mut header = RecordIndexHeader {
                     num_entries: 0,
+                    format: FormatInfo::new(COMPACTED_MAGIC, COMPACTED_VERSION, flags),
                     _pad: Default::default(),
                 };
                 pwrite_all(&mut index_random, header.as_mut_bytes(), 0)?;
             }
         };
+        // we only know how to append in the current format
+        let format = read_header(&index_random)?.format;
+        let version = format.check(COMPACTED_MAGIC, COMPACTED_VERSION, COMPACTED_FLAGS)?;
+        if version != COMPACTED_VERSION {
+            anyhow::bail!(
+                "can't append to version {version} compacted store {}",
+                dir.display()
+            );
+        }
 
         Ok(CompactedStorageWriter {
+            dir: dir.to_path_buf(),
             index,
             links,
             index_random,
+            wide: format.flags & COMPACTED_FLAG_WIDE_POSITIONS != 0,
 
             last_target: None,
         })
     }
 
     pub fn log_backlinks(&mut self, target: &RecordId, sources: &BTreeSet<RecordId>) -> Result<()> {
+        self.log_backlinks_with_cids(target, sources, &BTreeMap::new())
+    }
+
+    /// like `log_backlinks`, also storing the cids of the sources that are in `cids`
+    pub fn log_backlinks_with_cids(
+        &mut self,
+        target: &RecordId,
+        sources: &BTreeSet<RecordId>,
+        cids: &BTreeMap<RecordId, CidHash>,
+    ) -> Result<()> {
         if let Some(last_target) = self.last_target {
             debug_assert!(&last_target <= target, "links must be logged in order!");
         }
         self.last_target.replace(*target);
 
         let mut links_pos = self.links.stream_position()?;
-        if links_pos % POS_ALIGN != 0 {
+        if !links_pos.is_multiple_of(POS_ALIGN) {
             let zeroes = [0u8; POS_ALIGN as usize];
             let padding = POS_ALIGN - links_pos % POS_ALIGN;
             self.links.write_all(&zeroes[..padding as usize])?;
             links_pos += padding;
         }
 
-        let entry = RecordIndexEntry {
-            target: *target,
-            count: sources.len().try_into().expect("too many!"),
-            position: (links_pos / 32) as u32,
+        let with_paths = sources.iter().any(|source| source.path() != 0);
+        let cids = match sources.iter().any(|source| cids.contains_key(source)) {
+            true => sources
+                .iter()
+                .map(|source| cids.get(source).copied().unwrap_or_default())
+                .collect(),
+            false => Vec::new(),
         };
-
-        for source in sources.iter() {
-            self.links.write_all(&source.rkey.to_le_bytes())?;
+        let mut block = encode_link_block(sources, with_paths, &cids);
+        let mut target = *target;
+        target._flags = 0.into();
+        if with_paths {
+            target._flags.0 |= RECORD_FLAG_LINK_PATHS;
         }
-        for source in sources.iter() {
-            let mut collection_buf = unsigned_varint::encode::u32_buffer();
-            let collection_slice =
-                unsigned_varint::encode::u32(source.collection, &mut collection_buf);
-            self.links.write_all(collection_slice)?;
+        if !cids.is_empty() {
+            target._flags.0 |= RECORD_FLAG_CIDS;
         }
-        for source in sources.iter() {
-            let mut did_buf = unsigned_varint::encode::u64_buffer();
-            let did_slice = unsigned_varint::encode::u64(source.did, &mut did_buf);
-            self.links.write_all(did_slice)?;
+        if sources.len() >= COMPRESSED_BLOCK_MIN_COUNT {
+            let compressed = encode_compressed_link_block(sources, with_paths, &cids)?;
+            if compressed.len() < block.len() {
+                block = compressed;
+                target._flags.0 |= RECORD_FLAG_COMPRESSED_BLOCK;
+            }
         }
 
+        let entry = RecordIndexEntry {
+            target,
+            count: sources.len().try_into().expect("too many!"),
+            position: links_pos / POS_ALIGN,
+        };
+        // check this before writing anything, so that we don't leave an orphaned block behind
+        let narrow_entry = match self.wide {
+            true => None,
+            false => Some(NarrowRecordIndexEntry {
+                target,
+                count: entry.count,
+                position: u32::try_from(entry.position)
+                    .context("links.dat is too big for narrow positions")?,
+            }),
+        };
+
+        self.links.write_all(&block)?;
+
         {
             flock(self.index_random.as_raw_fd(), FlockArg::LockExclusive)?;
-            let mut header: RecordIndexHeader = {
-                let mut header_buf = [0u8; INDEX_HEADER_SIZE];
-                pread_all(&self.index_random, &mut header_buf, 0)?;
-                zerocopy::transmute!(header_buf)
-            };
-            entry.write_to_io(&mut self.index)?;
+            let mut header = read_header(&self.index_random)?;
+            if is_compressed(&entry) {
+                // readers that can't decompress blocks have to refuse this store from now on
+                header.format.flags |= COMPACTED_FLAG_COMPRESSED_BLOCKS;
+            }
+            if has_paths(&entry) {
+                header.format.flags |= COMPACTED_FLAG_LINK_PATHS;
+            }
+            if has_cids(&entry) {
+                header.format.flags |= COMPACTED_FLAG_CIDS;
+            }
+            match narrow_entry {
+                Some(narrow_entry) => self.index.write_all(narrow_entry.as_bytes())?,
+                None => self.index.write_all(entry.as_bytes())?,
+            }
             header.num_entries += 1;
             pwrite_all(&self.index_random, header.as_bytes(), 0)?;
             let _ = flock(self.index_random.as_raw_fd(), FlockArg::Unlock);
@@ -147,59 +615,120 @@ impl CompactedStorageWriter {
 
         Ok(())
     }
+
+    /// builds the bloom filter once everything has been logged
+    pub fn finish(self) -> Result<()> {
+        self.index.sync_all()?;
+        self.links.sync_all()?;
+        CompactedBloomFilter::build(&self.dir)
+    }
 }
 
+// files are mapped rather than read, so lookups don't need a syscall per step and the reader
+// can be shared between threads. compacted stores are only ever published complete, and after
+// that the only thing that changes in place is tombstone bits, so the maps never go stale
 pub struct CompactedStorageReader {
-    index: File, // read
-    links: File, // read
+    index: Mmap,
+    links: Mmap,
+    version: u32,
+    flags: u64,
+    wide: bool,
+    bloom: Option<CompactedBloomFilter>,
+}
+
+// the API shares readers between its threads
+const _: fn() = || {
+    fn assert_sync<T: Send + Sync>() {}
+    assert_sync::<CompactedStorageReader>();
+};
+
+fn map_file(path: &Path) -> Result<Mmap> {
+    let file = File::options().read(true).open(path)?;
+    // SAFETY: see CompactedStorageReader, nothing truncates or rewrites these files under us
+    Ok(unsafe { Mmap::map(&file) }?)
 }
 
 impl CompactedStorageReader {
     pub fn new(dir: impl AsRef<Path>) -> Result<Self> {
-        let index = File::options()
-            .read(true)
-            .open(dir.as_ref().join("index.dat"))?;
-        let links = File::options()
-            .read(true)
-            .open(dir.as_ref().join("links.dat"))?;
-        Ok(Self { index, links })
+        let index = map_file(&dir.as_ref().join("index.dat"))?;
+        let links = map_file(&dir.as_ref().join("links.dat"))?;
+        let (header, _) = RecordIndexHeader::read_from_prefix(&index)
+            .map_err(|_| anyhow::anyhow!("index.dat is too short for a header"))?;
+        let version = header
+            .format
+            .check(COMPACTED_MAGIC, COMPACTED_VERSION, COMPACTED_FLAGS)
+            .with_context(|| format!("can't read compacted store {}", dir.as_ref().display()))?;
+        // a missing or stale filter just means we can't skip lookups
+        let bloom = CompactedBloomFilter::open(&dir)
+            .ok()
+            .filter(|bloom| bloom.num_entries() == header.num_entries);
+        Ok(Self {
+            index,
+            links,
+            version,
+            flags: header.format.flags,
+            wide: header.format.flags & COMPACTED_FLAG_WIDE_POSITIONS != 0,
+            bloom,
+        })
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn num_entries(&self) -> Result<u64> {
+        let (header, _) = RecordIndexHeader::read_from_prefix(&self.index)
+            .map_err(|_| anyhow::anyhow!("index.dat is too short for a header"))?;
+        Ok(header.num_entries)
     }
 
-    // simple binary search
+    fn index_entry(&self, i: usize) -> Result<RecordIndexEntry> {
+        let entry_size = index_entry_size(self.wide);
+        let start = INDEX_HEADER_SIZE + i * entry_size;
+        let buf = self
+            .index
+            .get(start..start + entry_size)
+            .with_context(|| format!("index entry {i} is past the end of index.dat"))?;
+        Ok(decode_index_entry(self.wide, buf))
+    }
+
+    // simple binary search, unless the bloom filter rules the target out
     pub fn find_index_entry(&self, target: &RecordId) -> Result<Option<RecordIndexEntry>> {
-        let header: RecordIndexHeader = {
-            let mut header_buf = [0u8; INDEX_HEADER_SIZE];
-            pread_all(&self.index, &mut header_buf, 0)?;
-            zerocopy::transmute!(header_buf)
-        };
+        if self
+            .bloom
+            .as_ref()
+            .is_some_and(|bloom| !bloom.may_contain(target))
+        {
+            return Ok(None);
+        }
 
         let mut start = 0;
-        let mut end = header.num_entries as usize;
-        loop {
+        let mut end = self.num_entries()? as usize;
+        while start < end {
             let i = start + (end - start) / 2;
-
-            let entry: RecordIndexEntry = {
-                let mut entry_buf = [0u8; INDEX_ENTRY_SIZE];
-                pread_all(&self.index, &mut entry_buf, i * INDEX_ENTRY_SIZE)?;
-                zerocopy::transmute!(entry_buf)
-            };
+            let entry = self.index_entry(i)?;
 
             match entry.target.cmp(target) {
                 Ordering::Less => start = i + 1,
                 Ordering::Greater => end = i,
                 Ordering::Equal => return Ok(Some(entry)),
             }
-
-            if start >= end {
-                break;
-            }
         }
 
         Ok(None)
     }
 
+    /// walks the whole index in order
+    pub fn index_entries(&self) -> Result<IndexEntries<'_>> {
+        Ok(IndexEntries {
+            reader: self,
+            next: 0,
+            end: self.num_entries()? as usize,
+        })
+    }
+
     pub fn read_backlinks(
-        &mut self,
+        &self,
         target: &RecordId,
         records: &mut BTreeSet<RecordId>,
     ) -> Result<()> {
@@ -207,29 +736,527 @@ impl CompactedStorageReader {
             return Ok(());
         };
 
-        let mut rkeys_buf = vec![0u8; entry.count as usize * 8];
-        self.links
-            .seek(SeekFrom::Start(entry.position as u64 * POS_ALIGN))?;
-        self.links.read_exact(&mut rkeys_buf)?;
-        let (_start, rkeys, _end) = unsafe { rkeys_buf.as_mut_slice().align_to_mut::<u64>() };
+        self.read_backlinks_from_index_entry(&entry, records)
+    }
+
+    pub fn read_backlinks_from_index_entry(
+        &self,
+        entry: &RecordIndexEntry,
+        records: &mut BTreeSet<RecordId>,
+    ) -> Result<()> {
+        self.read_backlinks_with_cids_from_index_entry(entry, records, &mut BTreeMap::new())
+    }
 
-        let mut collections = Vec::<u32>::with_capacity(entry.count as usize);
-        for _ in 0..entry.count {
-            collections.push(unsigned_varint::io::read_u32(&mut self.links)?);
+    /// like `read_backlinks`, but also adds the cid of every source that has one to `cids`
+    pub fn read_backlinks_with_cids(
+        &self,
+        target: &RecordId,
+        records: &mut BTreeSet<RecordId>,
+        cids: &mut BTreeMap<RecordId, CidHash>,
+    ) -> Result<()> {
+        let Some(entry) = self.find_index_entry(target)? else {
+            return Ok(());
+        };
+
+        self.read_backlinks_with_cids_from_index_entry(&entry, records, cids)
+    }
+
+    pub fn read_backlinks_with_cids_from_index_entry(
+        &self,
+        entry: &RecordIndexEntry,
+        records: &mut BTreeSet<RecordId>,
+        cids: &mut BTreeMap<RecordId, CidHash>,
+    ) -> Result<()> {
+        let block = read_link_block(&self.links, entry)?;
+        for (i, ((rkey, collection), did)) in block
+            .rkeys
+            .into_iter()
+            .zip(block.collections)
+            .zip(block.dids)
+            .enumerate()
+        {
+            let (collection, deleted) = decode_collection(self.version, collection);
+            if deleted {
+                continue;
+            }
+            let path = block.paths.get(i).copied().unwrap_or_default();
+            let source = RecordId::new(did, collection, rkey).with_path(path);
+            records.insert(source);
+            if let Some(&cid) = block.cids.get(i).filter(|cid| **cid != 0) {
+                cids.entry(source).or_insert(cid);
+            }
         }
-        let mut dids = Vec::<u64>::with_capacity(entry.count as usize);
-        for _ in 0..entry.count {
-            dids.push(unsigned_varint::io::read_u64(&mut self.links)?);
+
+        Ok(())
+    }
+}
+
+pub struct IndexEntries<'a> {
+    reader: &'a CompactedStorageReader,
+    next: usize,
+    end: usize,
+}
+
+impl Iterator for IndexEntries<'_> {
+    type Item = Result<RecordIndexEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next == self.end {
+            return None;
+        }
+        self.next += 1;
+        Some(self.reader.index_entry(self.next - 1))
+    }
+}
+
+/// in-place edits (i.e. tombstones) to an already-written compacted store
+pub struct CompactedStorageMutator {
+    reader: CompactedStorageReader,
+    links: File, // read, write
+}
+
+impl CompactedStorageMutator {
+    pub fn new(dir: impl AsRef<Path>) -> Result<Self> {
+        let reader = CompactedStorageReader::new(&dir)?;
+        let links = File::options()
+            .read(true)
+            .write(true)
+            .open(dir.as_ref().join("links.dat"))?;
+        Ok(Self { reader, links })
+    }
+
+    /// sets the tombstone bit on `source` in `target`'s block,
+    /// returning whether it was found (and not already deleted)
+    pub fn mark_deleted(&mut self, target: &RecordId, source: &RecordId) -> Result<bool> {
+        if self.reader.version == 0 {
+            // there's no tombstone bit, but merging the store rewrites it in the current format
+            anyhow::bail!("compacted store predates tombstones, it has to be merged first");
         }
+        let Some(entry) = self.reader.find_index_entry(target)? else {
+            return Ok(false);
+        };
+        // setting a bit is a read-modify-write of its byte, so two mutators flipping bits
+        // next to each other would lose one of them
+        let links_raw_fd = self.links.as_raw_fd();
+        flock(links_raw_fd, FlockArg::LockExclusive)?;
+        let result = mark_deleted_in_block(&self.reader.links, &self.links, &entry, source);
+        let _ = flock(links_raw_fd, FlockArg::Unlock);
+        result
+    }
+}
 
-        for ((rkey, collection), did) in rkeys
+fn varint_len_u32(value: u32) -> u64 {
+    unsigned_varint::encode::u32(value, &mut unsigned_varint::encode::u32_buffer()).len() as u64
+}
+
+fn varint_len_u64(value: u64) -> u64 {
+    unsigned_varint::encode::u64(value, &mut unsigned_varint::encode::u64_buffer()).len() as u64
+}
+
+/// checks a compacted store for damage, returning a description of every problem found.
+/// `on_record` sees every target and source, e.g. to check that their ids resolve
+pub fn fsck(dir: impl AsRef<Path>, mut on_record: impl FnMut(&RecordId)) -> Result<Vec<String>> {
+    let reader = CompactedStorageReader::new(&dir)?;
+    let mut problems = Vec::new();
+
+    if reader.bloom.is_none() && dir.as_ref().join(BLOOM_FILE).exists() {
+        problems.push(format!("{BLOOM_FILE} is unreadable or out of date"));
+    }
+
+    let num_entries = reader.num_entries()?;
+    let index_len = reader.index.len() as u64;
+    let expected_len =
+        INDEX_HEADER_SIZE as u64 + num_entries * index_entry_size(reader.wide) as u64;
+    if index_len != expected_len {
+        problems.push(format!(
+            "index.dat is {index_len} bytes long, but {num_entries} entries need {expected_len}"
+        ));
+        if index_len < expected_len {
+            return Ok(problems);
+        }
+    }
+    let links_len = reader.links.len() as u64;
+
+    let mut last_target: Option<RecordId> = None;
+    let mut last_end = 0;
+    for (i, entry) in reader.index_entries()?.enumerate() {
+        let entry = entry?;
+        let target = entry.target;
+        on_record(&target);
+
+        if reader
+            .bloom
+            .as_ref()
+            .is_some_and(|bloom| !bloom.may_contain(&target))
+        {
+            problems.push(format!(
+                "index entry {i} ({target:?}) is missing from {BLOOM_FILE}"
+            ));
+        }
+        if last_target.is_some_and(|last_target| last_target >= target) {
+            problems.push(format!("index entry {i} ({target:?}) is out of order"));
+        }
+        last_target = Some(target);
+
+        // positions are stored divided by POS_ALIGN, so they're always aligned;
+        // what can go wrong is them pointing into (or past) somebody else's block
+        let start = entry.position * POS_ALIGN;
+        if start < last_end || start >= links_len {
+            problems.push(format!(
+                "index entry {i} ({target:?}) has its block at {start}, which overlaps the previous one or is out of bounds"
+            ));
+            continue;
+        }
+        if entry.count == 0 {
+            problems.push(format!("index entry {i} ({target:?}) has no backlinks"));
+        }
+
+        if is_compressed(&entry) && reader.flags & COMPACTED_FLAG_COMPRESSED_BLOCKS == 0 {
+            problems.push(format!(
+                "index entry {i} ({target:?}) has a compressed block, but the header doesn't say there are any"
+            ));
+        }
+        if has_paths(&entry) && reader.flags & COMPACTED_FLAG_LINK_PATHS == 0 {
+            problems.push(format!(
+                "index entry {i} ({target:?}) has a block with paths, but the header doesn't say there are any"
+            ));
+        }
+        if has_cids(&entry) && reader.flags & COMPACTED_FLAG_CIDS == 0 {
+            problems.push(format!(
+                "index entry {i} ({target:?}) has a block with cids, but the header doesn't say there are any"
+            ));
+        }
+        let block = match read_link_block(&reader.links, &entry) {
+            Ok(block) => block,
+            Err(e) => {
+                problems.push(format!(
+                    "index entry {i} ({target:?}) has a block that doesn't decode: {e}"
+                ));
+                continue;
+            }
+        };
+
+        let end = start + block.len;
+        let mut last_source: Option<RecordId> = None;
+        for (j, ((rkey, collection), did)) in block
+            .rkeys
             .iter()
-            .zip(collections.into_iter())
-            .zip(dids.into_iter())
+            .zip(&block.collections)
+            .zip(&block.dids)
+            .enumerate()
         {
-            records.insert(RecordId::new(did, collection, *rkey));
+            let (collection, _) = decode_collection(reader.version, *collection);
+            let path = block.paths.get(j).copied().unwrap_or_default();
+            let source = RecordId::new(*did, collection, *rkey).with_path(path);
+            on_record(&source);
+            if last_source.is_some_and(|last_source| last_source >= source) {
+                problems.push(format!(
+                    "index entry {i} ({target:?}) has its sources out of order at {source:?}"
+                ));
+                break;
+            }
+            last_source = Some(source);
+        }
+        if end > links_len {
+            problems.push(format!(
+                "index entry {i} ({target:?}) has a block that runs past the end of links.dat"
+            ));
         }
+        last_end = end;
+    }
 
-        Ok(())
+    Ok(problems)
+}
+
+#[test]
+fn test_tombstones() -> Result<()> {
+    let dir = crate::test_util::TempDir::new();
+    let target = RecordId::new(1, 1, 1);
+    let mut sources = (0..300)
+        .map(|i| RecordId::new(i, i as u32 * 7, i * 1000))
+        .collect::<BTreeSet<_>>();
+    // shares an rkey with the one we delete
+    let neighbour = RecordId::new(999, 3, 150_000);
+    sources.insert(neighbour);
+    CompactedStorageWriter::new(&dir)?.log_backlinks(&target, &sources)?;
+
+    let deleted = RecordId::new(150, 1050, 150_000);
+    let mut mutator = CompactedStorageMutator::new(&dir)?;
+    assert!(mutator.mark_deleted(&target, &deleted)?);
+    assert!(!mutator.mark_deleted(&target, &deleted)?);
+
+    let mut records = BTreeSet::new();
+    CompactedStorageReader::new(&dir)?.read_backlinks(&target, &mut records)?;
+    assert_eq!(records.len(), sources.len() - 1);
+    assert!(!records.contains(&deleted));
+    assert!(records.contains(&neighbour));
+
+    // tombstones don't upset fsck, but a block pointing past the end of links.dat does
+    let mut seen = 0;
+    assert!(fsck(&dir, |_| seen += 1)?.is_empty());
+    assert_eq!(seen, sources.len() + 1);
+    let position = std::mem::offset_of!(NarrowRecordIndexEntry, position) + INDEX_HEADER_SIZE;
+    pwrite_all(
+        File::options().write(true).open(dir.join("index.dat"))?,
+        1000u32.as_bytes(),
+        position,
+    )?;
+    assert_eq!(fsck(&dir, |_| {})?.len(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn test_format_versions() -> Result<()> {
+    let dir = crate::test_util::TempDir::new();
+    let target = RecordId::new(1, 1, 1);
+    let source = RecordId::new(2, 4, 2);
+    CompactedStorageWriter::new(&dir)?.log_backlinks(&target, &BTreeSet::from([source]))?;
+    assert_eq!(
+        CompactedStorageReader::new(&dir)?.version(),
+        COMPACTED_VERSION
+    );
+
+    let index = File::options()
+        .read(true)
+        .write(true)
+        .open(dir.join("index.dat"))?;
+    let write_format = |format: FormatInfo| {
+        let mut header = read_header(&index)?;
+        header.format = format;
+        pwrite_all(&index, header.as_bytes(), 0)
+    };
+
+    write_format(FormatInfo::new(COMPACTED_MAGIC, COMPACTED_VERSION + 1, 0))?;
+    assert!(CompactedStorageReader::new(&dir).is_err());
+    write_format(FormatInfo::new(COMPACTED_MAGIC, COMPACTED_VERSION, 1 << 63))?;
+    assert!(CompactedStorageReader::new(&dir).is_err());
+    write_format(FormatInfo::new(*b"nope", COMPACTED_VERSION, 0))?;
+    assert!(CompactedStorageReader::new(&dir).is_err());
+
+    // unversioned stores didn't shift their collections
+    write_format(FormatInfo::new([0; 4], 0, 0))?;
+    let mut records = BTreeSet::new();
+    CompactedStorageReader::new(&dir)?.read_backlinks(&target, &mut records)?;
+    assert!(records.first() == Some(&RecordId::new(2, 8, 2)));
+    assert!(CompactedStorageMutator::new(&dir)?
+        .mark_deleted(&target, &source)
+        .is_err());
+    assert!(CompactedStorageWriter::new(&dir).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_wide_positions() -> Result<()> {
+    let dir = crate::test_util::TempDir::new();
+    let targets = (0..100).map(|i| RecordId::new(1, 1, i)).collect::<Vec<_>>();
+    let sources = (0..3)
+        .map(|i| RecordId::new(2, 2, i))
+        .collect::<BTreeSet<_>>();
+
+    let mut writer = CompactedStorageWriter::new_for_size(&dir, NARROW_LINKS_LIMIT)?;
+    assert!(writer.wide);
+    for target in &targets {
+        writer.log_backlinks(target, &sources)?;
+    }
+    drop(writer);
+
+    let reader = CompactedStorageReader::new(&dir)?;
+    assert!(reader.wide);
+    assert_eq!(
+        reader.index.len() as u64 as usize,
+        INDEX_HEADER_SIZE + targets.len() * WIDE_INDEX_ENTRY_SIZE
+    );
+    for target in targets.iter().step_by(7) {
+        let mut records = BTreeSet::new();
+        reader.read_backlinks_from_index_entry(
+            &reader.find_index_entry(target)?.unwrap(),
+            &mut records,
+        )?;
+        assert_eq!(records, sources);
+    }
+    assert_eq!(reader.index_entries()?.count(), targets.len());
+    assert!(fsck(&dir, |_| {})?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_compressed_blocks() -> Result<()> {
+    let dir = crate::test_util::TempDir::new();
+    let (small, big) = (RecordId::new(1, 1, 1), RecordId::new(1, 1, 2));
+    let small_sources = BTreeSet::from([RecordId::new(2, 2, 2)]);
+    // tid-ish rkeys from a handful of collections, like a popular post's likes
+    let big_sources = (0..1000)
+        .map(|i| RecordId::new(i * 31, 4 + (i % 3 == 0) as u32, (1 << 50) + i * 1000))
+        .collect::<BTreeSet<_>>();
+
+    let mut writer = CompactedStorageWriter::new(&dir)?;
+    writer.log_backlinks(&small, &small_sources)?;
+    writer.log_backlinks(&big, &big_sources)?;
+    drop(writer);
+
+    let reader = CompactedStorageReader::new(&dir)?;
+    assert_ne!(reader.flags & COMPACTED_FLAG_COMPRESSED_BLOCKS, 0);
+    assert!(!is_compressed(&reader.find_index_entry(&small)?.unwrap()));
+    let big_entry = reader.find_index_entry(&big)?.unwrap();
+    assert!(is_compressed(&big_entry));
+    assert!(
+        read_link_block(&reader.links, &big_entry)?.len
+            < encode_link_block(&big_sources, false, &[]).len() as u64 / 4
+    );
+
+    let mut records = BTreeSet::new();
+    reader.read_backlinks_from_index_entry(&big_entry, &mut records)?;
+    assert_eq!(records, big_sources);
+
+    // tombstones still work, and only hit the one source
+    let deleted = *big_sources.iter().nth(500).unwrap();
+    let mut mutator = CompactedStorageMutator::new(&dir)?;
+    assert!(mutator.mark_deleted(&big, &deleted)?);
+    assert!(!mutator.mark_deleted(&big, &deleted)?);
+    assert!(mutator.mark_deleted(&small, small_sources.first().unwrap())?);
+    let mut records = BTreeSet::new();
+    reader.read_backlinks(&big, &mut records)?;
+    reader.read_backlinks(&small, &mut records)?;
+    assert_eq!(records.len(), big_sources.len() - 1);
+    assert!(!records.contains(&deleted));
+
+    let mut seen = 0;
+    assert!(fsck(&dir, |_| seen += 1)?.is_empty());
+    assert_eq!(seen, 2 + big_sources.len() + small_sources.len());
+
+    Ok(())
+}
+
+#[test]
+fn test_shared_reader() -> Result<()> {
+    let dir = crate::test_util::TempDir::new();
+    let targets = (0..1000)
+        .map(|i| RecordId::new(1, 1, i))
+        .collect::<Vec<_>>();
+
+    // nothing written yet, so links.dat is empty
+    let mut writer = CompactedStorageWriter::new(&dir)?;
+    assert!(CompactedStorageReader::new(&dir)?
+        .find_index_entry(&targets[0])?
+        .is_none());
+    for (i, target) in targets.iter().enumerate() {
+        writer.log_backlinks(target, &BTreeSet::from([RecordId::new(2, 2, i as u64)]))?;
     }
+    writer.finish()?;
+
+    let reader = CompactedStorageReader::new(&dir)?;
+    std::thread::scope(|s| {
+        let handles = (0..4)
+            .map(|t| {
+                let (reader, targets) = (&reader, &targets);
+                s.spawn(move || -> Result<()> {
+                    for (i, target) in targets.iter().enumerate().skip(t).step_by(4) {
+                        let mut records = BTreeSet::new();
+                        reader.read_backlinks(target, &mut records)?;
+                        assert_eq!(records, BTreeSet::from([RecordId::new(2, 2, i as u64)]));
+                    }
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .try_for_each(|handle| handle.join().unwrap())
+    })?;
+    assert_eq!(reader.index_entries()?.count(), targets.len());
+
+    Ok(())
+}
+
+#[test]
+fn test_link_paths() -> Result<()> {
+    let dir = crate::test_util::TempDir::new();
+    let (plain, small, big) = (
+        RecordId::new(1, 1, 1),
+        RecordId::new(1, 1, 2),
+        RecordId::new(1, 1, 3),
+    );
+    let plain_sources = BTreeSet::from([RecordId::new(2, 2, 2)]);
+    let small_sources =
+        BTreeSet::from([RecordId::new(2, 2, 2).with_path(1), RecordId::new(3, 2, 2)]);
+    let big_sources = (0..1000)
+        .map(|i| RecordId::new(i * 31, 4, (1 << 50) + i * 1000).with_path(1 + (i % 7 == 0) as u32))
+        .collect::<BTreeSet<_>>();
+
+    let mut writer = CompactedStorageWriter::new(&dir)?;
+    writer.log_backlinks(&plain, &plain_sources)?;
+    writer.log_backlinks(&small, &small_sources)?;
+    writer.log_backlinks(&big, &big_sources)?;
+    drop(writer);
+
+    let reader = CompactedStorageReader::new(&dir)?;
+    assert_ne!(reader.flags & COMPACTED_FLAG_LINK_PATHS, 0);
+    assert!(!has_paths(&reader.find_index_entry(&plain)?.unwrap()));
+    let big_entry = reader.find_index_entry(&big)?.unwrap();
+    assert!(has_paths(&big_entry) && is_compressed(&big_entry));
+
+    for (target, sources) in [
+        (plain, &plain_sources),
+        (small, &small_sources),
+        (big, &big_sources),
+    ] {
+        let mut records = BTreeSet::new();
+        reader.read_backlinks(&target, &mut records)?;
+        assert!(records
+            .iter()
+            .zip(sources)
+            .all(|(record, source)| record == source && record.path() == source.path()));
+        assert_eq!(records.len(), sources.len());
+    }
+
+    // tombstones still land on the right source
+    let deleted = *big_sources.iter().nth(701).unwrap();
+    assert!(CompactedStorageMutator::new(&dir)?.mark_deleted(&big, &deleted)?);
+    let mut records = BTreeSet::new();
+    reader.read_backlinks(&big, &mut records)?;
+    assert!(!records.contains(&deleted));
+    assert_eq!(records.iter().filter(|r| r.path() == 2).count(), 143);
+    assert!(fsck(&dir, |_| {})?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_cids() -> Result<()> {
+    let dir = crate::test_util::TempDir::new();
+    let (small, big) = (RecordId::new(1, 1, 1), RecordId::new(1, 1, 2));
+    let small_sources = BTreeSet::from([RecordId::new(2, 2, 2), RecordId::new(3, 2, 2)]);
+    let big_sources = (0..1000)
+        .map(|i| RecordId::new(i * 31, 4, (1 << 50) + i * 1000))
+        .collect::<BTreeSet<_>>();
+    // every other source has a cid, and one of them points at another version
+    let cids = small_sources
+        .iter()
+        .chain(&big_sources)
+        .step_by(2)
+        .enumerate()
+        .map(|(i, source)| (*source, 1 + (i == 3) as u64))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut writer = CompactedStorageWriter::new(&dir)?;
+    writer.log_backlinks_with_cids(&small, &small_sources, &cids)?;
+    writer.log_backlinks_with_cids(&big, &big_sources, &cids)?;
+    drop(writer);
+
+    let reader = CompactedStorageReader::new(&dir)?;
+    assert_ne!(reader.flags & COMPACTED_FLAG_CIDS, 0);
+    let big_entry = reader.find_index_entry(&big)?.unwrap();
+    assert!(has_cids(&big_entry) && is_compressed(&big_entry));
+
+    let (mut records, mut found) = (BTreeSet::new(), BTreeMap::new());
+    reader.read_backlinks_with_cids(&small, &mut records, &mut found)?;
+    reader.read_backlinks_with_cids(&big, &mut records, &mut found)?;
+    assert_eq!(records.len(), small_sources.len() + big_sources.len());
+    assert_eq!(found, cids);
+    assert!(fsck(&dir, |_| {})?.is_empty());
+
+    Ok(())
 }
@@ -0,0 +1,161 @@
+use std::{fs::File, mem::size_of, path::Path};
+
+use anyhow::Result;
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
+
+use crate::data::record::RecordId;
+
+use super::{
+    compacted::CompactedStorageReader, live_hash::hash_record_id, pread_all, pwrite_all, FormatInfo,
+};
+
+// a bloom filter over the targets of a compacted store, kept in bloom.dat next to index.dat,
+// so that looking up a target that isn't in the store doesn't have to touch the index at all.
+// it's only derived from index.dat: stores without one (or with a stale one) just don't get
+// to skip any lookups.
+
+#[derive(Debug, Clone, Copy, KnownLayout, Immutable, IntoBytes, FromBytes)]
+#[repr(C)]
+pub struct BloomHeader {
+    // number of index.dat entries the filter was built from
+    pub num_entries: u64,
+    // always a multiple of 64
+    pub num_bits: u64,
+    pub format: FormatInfo,
+}
+const HEADER_SIZE: usize = size_of::<BloomHeader>();
+const _: [(); 32] = [(); HEADER_SIZE];
+
+pub const BLOOM_FILE: &str = "bloom.dat";
+
+const BLOOM_MAGIC: [u8; 4] = *b"BSBF";
+const BLOOM_VERSION: u32 = 1;
+const BLOOM_FLAGS: u64 = 0;
+
+// about a 1% false positive rate
+const BITS_PER_ENTRY: u64 = 10;
+const NUM_HASHES: u64 = 7;
+
+pub struct CompactedBloomFilter {
+    num_entries: u64,
+    bits: Vec<u64>,
+}
+
+impl CompactedBloomFilter {
+    /// loads the whole filter into memory
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let file = File::open(dir.as_ref().join(BLOOM_FILE))?;
+        let mut buf = [0u8; HEADER_SIZE];
+        pread_all(&file, &mut buf, 0)?;
+        let header: BloomHeader = zerocopy::transmute!(buf);
+        header
+            .format
+            .check(BLOOM_MAGIC, BLOOM_VERSION, BLOOM_FLAGS)?;
+        if header.num_bits == 0 || !header.num_bits.is_multiple_of(64) {
+            anyhow::bail!("bloom filter has {} bits", header.num_bits);
+        }
+
+        let mut bits = vec![0u64; (header.num_bits / 64) as usize];
+        pread_all(&file, bits.as_mut_bytes(), HEADER_SIZE)?;
+        Ok(Self {
+            num_entries: header.num_entries,
+            bits,
+        })
+    }
+
+    /// (re)builds bloom.dat from the index of the compacted store in `dir`
+    pub fn build(dir: impl AsRef<Path>) -> Result<()> {
+        let reader = CompactedStorageReader::new(&dir)?;
+        let num_entries = reader.num_entries()?;
+        let num_bits = (num_entries * BITS_PER_ENTRY).div_ceil(64).max(1) * 64;
+
+        let mut filter = Self {
+            num_entries,
+            bits: vec![0u64; (num_bits / 64) as usize],
+        };
+        for entry in reader.index_entries()? {
+            filter.insert(&entry?.target);
+        }
+
+        // written next to it and renamed into place, so readers never see half of it
+        let path = dir.as_ref().join(BLOOM_FILE);
+        let tmp_path = path.with_extension("dat.tmp");
+        let file = File::create(&tmp_path)?;
+        let header = BloomHeader {
+            num_entries,
+            num_bits,
+            format: FormatInfo::new(BLOOM_MAGIC, BLOOM_VERSION, BLOOM_FLAGS),
+        };
+        pwrite_all(&file, header.as_bytes(), 0)?;
+        pwrite_all(&file, filter.bits.as_bytes(), HEADER_SIZE)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, &path)?;
+
+        Ok(())
+    }
+
+    pub fn num_entries(&self) -> u64 {
+        self.num_entries
+    }
+
+    // double hashing: bit i is h1 + i × h2
+    fn bit_indices(&self, target: &RecordId) -> impl Iterator<Item = u64> {
+        let hash = hash_record_id(target);
+        let (h1, h2) = (hash, hash.rotate_left(32) | 1);
+        let num_bits = self.bits.len() as u64 * 64;
+        (0..NUM_HASHES).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+
+    fn insert(&mut self, target: &RecordId) {
+        for bit in self.bit_indices(target).collect::<Vec<_>>() {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    /// false if `target` is definitely not in the store
+    pub fn may_contain(&self, target: &RecordId) -> bool {
+        self.bit_indices(target)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+}
+
+#[test]
+fn test_bloom_filter() -> Result<()> {
+    use super::compacted::CompactedStorageWriter;
+    use std::collections::BTreeSet;
+
+    let dir = crate::test_util::TempDir::new();
+    let sources = BTreeSet::from([RecordId::new(2, 2, 2)]);
+
+    let mut writer = CompactedStorageWriter::new(&dir)?;
+    for i in 0..10_000 {
+        writer.log_backlinks(&RecordId::new(1, 1, i * 2), &sources)?;
+    }
+    writer.finish()?;
+
+    let filter = CompactedBloomFilter::open(&dir)?;
+    assert_eq!(filter.num_entries(), 10_000);
+    assert!((0..10_000).all(|i| filter.may_contain(&RecordId::new(1, 1, i * 2))));
+    let false_positives = (0..10_000)
+        .filter(|i| filter.may_contain(&RecordId::new(1, 1, i * 2 + 1)))
+        .count();
+    assert!(false_positives < 300, "{false_positives} false positives");
+
+    // the reader skips targets the filter rules out, and still finds the rest
+    let reader = CompactedStorageReader::new(&dir)?;
+    let mut records = BTreeSet::new();
+    reader.read_backlinks(&RecordId::new(1, 1, 1234), &mut records)?;
+    assert_eq!(records, sources);
+
+    // appending to the store makes the filter stale, so it gets dropped
+    let mut writer = CompactedStorageWriter::new(&dir)?;
+    assert!(!dir.join(BLOOM_FILE).exists());
+    writer.log_backlinks(&RecordId::new(1, 1, 1 << 40), &sources)?;
+    drop(writer);
+    let mut records = BTreeSet::new();
+    CompactedStorageReader::new(&dir)?
+        .read_backlinks(&RecordId::new(1, 1, 1 << 40), &mut records)?;
+    assert_eq!(records, sources);
+
+    Ok(())
+}
@@ -0,0 +1,360 @@
+use std::{
+    fs::File,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use nix::fcntl::{Flock, FlockArg};
+
+use crate::db::DbConnection;
+
+use super::deletion::{apply_pending_deletes, clear_pending_deletes_mark, mark_pending_deletes};
+
+// `compaction_in_progress` only says that somebody started compacting (or merging) a store.
+// whoever is actually working on it also holds a flock on compacted/<name>.lock, so a row
+// with the flag set that nobody holds the lock for was left behind by a process that died.
+//
+// output is always written to compacted/<name>.tmp first and only renamed into place once
+// everything in it has been fsynced, so a compacted/<name> directory is always complete.
+
+pub struct CompactionLock {
+    path: PathBuf,
+    _file: Flock<File>,
+}
+
+impl CompactionLock {
+    /// takes the lock for `name`, or returns `None` if somebody else is holding it
+    pub fn try_acquire(data_dir: &Path, name: &str) -> Result<Option<Self>> {
+        let dir = data_dir.join("compacted");
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{name}.lock"));
+        loop {
+            let file = File::options()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)?;
+
+            let file = match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+                Ok(file) => file,
+                Err((_, nix::errno::Errno::EWOULDBLOCK)) => return Ok(None),
+                Err((_, e)) => return Err(e.into()),
+            };
+            // whoever held it before us may have removed the file in the meantime, and then
+            // our lock is on a file that nobody else is going to open anymore
+            let locked = file.metadata()?;
+            match path.metadata() {
+                Ok(current) if (current.dev(), current.ino()) == (locked.dev(), locked.ino()) => {
+                    return Ok(Some(Self { path, _file: file }));
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// releases the lock for good, once the store it was protecting has been dealt with
+    pub fn remove(self) -> Result<()> {
+        std::fs::remove_file(&self.path)?;
+        Ok(())
+    }
+}
+
+pub fn tmp_dir(data_dir: &Path, name: &str) -> PathBuf {
+    data_dir.join("compacted").join(format!("{name}.tmp"))
+}
+
+/// fsyncs every file and directory under `dir` (and `dir` itself)
+pub fn sync_dir_all(dir: &Path) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            sync_dir_all(&entry.path())?;
+        } else {
+            File::open(entry.path())?.sync_all()?;
+        }
+    }
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// fsyncs `tmp_dir` and atomically renames it to `dir`
+pub fn publish_dir(tmp_dir: &Path, dir: &Path) -> Result<()> {
+    sync_dir_all(tmp_dir)?;
+    std::fs::rename(tmp_dir, dir)?;
+    if let Some(parent) = dir.parent() {
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// an upper bound on the size of the links.dat that compacting `live_dir` produces.
+/// a compacted source never takes more than the 32 bytes a live one does,
+/// and a block's padding never takes more than the 40 bytes its live index entry does
+pub fn max_compacted_links_size(live_dir: &Path) -> u64 {
+    ["index.dat", "links.dat"]
+        .into_iter()
+        .map(|file| {
+            live_dir
+                .join(file)
+                .metadata()
+                .map(|m| m.len())
+                .unwrap_or_default()
+        })
+        .sum()
+}
+
+/// claims live store `name` for compaction, unless somebody is compacting it right now.
+/// a store that was left claimed by a process that died gets claimed again
+pub fn claim_live_store(
+    data_dir: &Path,
+    db: &DbConnection,
+    name: &str,
+) -> Result<Option<CompactionLock>> {
+    let Some(lock) = CompactionLock::try_acquire(data_dir, name)? else {
+        return Ok(None);
+    };
+    mark_compaction_in_progress(db, name)?;
+    Ok(Some(lock))
+}
+
+/// sets `compaction_in_progress` for a live store whose lock we're already holding
+pub fn mark_compaction_in_progress(db: &DbConnection, name: &str) -> Result<()> {
+    let changed = db.execute(
+        "UPDATE data_stores SET compaction_in_progress = 1 WHERE name = ? AND type = 'live'",
+        [name],
+    )?;
+    if changed != 1 {
+        anyhow::bail!("{name} is not a live store");
+    }
+    Ok(())
+}
+
+/// the oldest live store that isn't being compacted, which may be one that was abandoned.
+/// it isn't marked as in progress yet (see `mark_compaction_in_progress`),
+/// but nobody else can claim it while we hold the returned lock
+pub fn find_live_store_to_compact(
+    data_dir: &Path,
+    db: &DbConnection,
+) -> Result<Option<(String, CompactionLock)>> {
+    let mut statement =
+        db.prepare("SELECT name FROM data_stores WHERE type = 'live' ORDER BY id ASC")?;
+    let names = statement
+        .query_map((), |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    for name in names {
+        if let Some(lock) = CompactionLock::try_acquire(data_dir, &name)? {
+            return Ok(Some((name, lock)));
+        }
+    }
+    Ok(None)
+}
+
+/// gets compacted/<name>.tmp ready to be compacted into.
+/// returns `None` if an earlier attempt already got as far as renaming it into place,
+/// in which case only `finish_compaction` is left to do
+pub fn prepare_compaction(
+    data_dir: &Path,
+    db: &DbConnection,
+    name: &str,
+) -> Result<Option<PathBuf>> {
+    // deletes from here on might not make it into the output, so finish_compaction redoes them
+    mark_pending_deletes(db, name)?;
+
+    if data_dir.join("compacted").join(name).exists() {
+        return Ok(None);
+    }
+
+    let tmp_dir = tmp_dir(data_dir, name);
+    if tmp_dir.exists() {
+        // left behind by an attempt that never finished
+        std::fs::remove_dir_all(&tmp_dir)?;
+    }
+    Ok(Some(tmp_dir))
+}
+
+/// moves the output of a compaction into place and marks the store as compacted
+pub fn finish_compaction(
+    data_dir: &Path,
+    db: &DbConnection,
+    name: &str,
+    lock: CompactionLock,
+) -> Result<()> {
+    let tmp_dir = tmp_dir(data_dir, name);
+    let output_dir = data_dir.join("compacted").join(name);
+    if tmp_dir.exists() {
+        apply_pending_deletes(db, name, &tmp_dir)?;
+        publish_dir(&tmp_dir, &output_dir)?;
+    }
+
+    db.execute(
+        "UPDATE data_stores SET type = 'compacted', compaction_in_progress = 0 WHERE name = ?",
+        [name],
+    )?;
+    // deletes that still went to the live store while it was being published
+    apply_pending_deletes(db, name, &output_dir)?;
+    clear_pending_deletes_mark(db, name)?;
+    lock.remove()?;
+
+    Ok(())
+}
+
+pub(crate) fn is_registered(db: &DbConnection, name: &str) -> Result<bool> {
+    Ok(db.query_row(
+        "SELECT EXISTS (SELECT 1 FROM data_stores WHERE name = ?)",
+        [name],
+        |row| row.get(0),
+    )?)
+}
+
+/// cleans up after compactions and merges whose process died.
+/// abandoned merges are released so they get picked again, and half-written output
+/// and stores that aren't registered anymore are removed.
+/// abandoned live store compactions are claimed and returned, so that they can be restarted
+pub fn recover_abandoned_compactions(
+    data_dir: &Path,
+    db: &DbConnection,
+) -> Result<Vec<(String, CompactionLock)>> {
+    let mut statement = db.prepare(
+        "SELECT name, type FROM data_stores WHERE compaction_in_progress = 1 ORDER BY id ASC",
+    )?;
+    let rows = statement
+        .query_map((), |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut abandoned = Vec::new();
+    for (name, store_type) in rows {
+        let Some(lock) = CompactionLock::try_acquire(data_dir, &name)? else {
+            continue;
+        };
+        if store_type == "live" {
+            abandoned.push((name, lock));
+        } else {
+            db.execute(
+                "UPDATE data_stores SET compaction_in_progress = 0 WHERE name = ?",
+                [&name],
+            )?;
+            lock.remove()?;
+        }
+    }
+
+    let compacted_dir = data_dir.join("compacted");
+    if compacted_dir.exists() {
+        for entry in std::fs::read_dir(&compacted_dir)?.filter_map(Result::ok) {
+            let Ok(file_name) = entry.file_name().into_string() else {
+                continue;
+            };
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            // merge inputs that were swapped out but never removed, and merge outputs that
+            // were published but never registered, are left behind as well. live stores
+            // being compacted publish under a name that's still registered, so they're safe
+            let (name, is_tmp) = match file_name.strip_suffix(".tmp") {
+                Some(name) => (name, true),
+                None => (file_name.as_str(), false),
+            };
+            if is_tmp && abandoned.iter().any(|(n, _)| n == name) {
+                // gets cleaned up when it's restarted
+                continue;
+            }
+            if !is_tmp && is_registered(db, name)? {
+                continue;
+            }
+            if let Some(lock) = CompactionLock::try_acquire(data_dir, name)? {
+                // whoever was holding the lock may have finished up in the meantime
+                if entry.path().exists() && (is_tmp || !is_registered(db, name)?) {
+                    std::fs::remove_dir_all(entry.path())?;
+                }
+                lock.remove()?;
+            }
+        }
+    }
+
+    // compactions and merges that died after publishing their output but before catching it
+    // up on deletes, or before getting anywhere at all
+    let mut statement = db.prepare("SELECT name FROM pending_delete_marks")?;
+    let marks = statement
+        .query_map((), |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for name in marks {
+        if abandoned.iter().any(|(n, _)| *n == name) {
+            // gets caught up when it's restarted
+            continue;
+        }
+        let Some(lock) = CompactionLock::try_acquire(data_dir, &name)? else {
+            continue;
+        };
+        let published: bool = db.query_row(
+            "SELECT EXISTS (SELECT 1 FROM data_stores WHERE name = ? AND type = 'compacted')",
+            [&name],
+            |row| row.get(0),
+        )?;
+        if published {
+            apply_pending_deletes(db, &name, &compacted_dir.join(&name))?;
+        }
+        clear_pending_deletes_mark(db, &name)?;
+        lock.remove()?;
+    }
+
+    Ok(abandoned)
+}
+
+#[test]
+fn test_abandoned_compaction() -> Result<()> {
+    use super::compacted::{CompactedStorageReader, CompactedStorageWriter};
+    use crate::data::record::RecordId;
+
+    let data_dir = crate::test_util::TempDir::new();
+    let db = rusqlite::Connection::open_in_memory()?;
+    crate::db::setup_db(&db)?;
+    db.execute(
+        "INSERT INTO data_stores (name, type, compaction_in_progress) VALUES ('a', 'live', 1), ('b', 'live', 1)",
+        (),
+    )?;
+
+    // 'a' was abandoned halfway through, 'b' is still being worked on
+    std::fs::create_dir_all(tmp_dir(&data_dir, "a"))?;
+    std::fs::write(tmp_dir(&data_dir, "a").join("index.dat"), b"garbage")?;
+    let b_lock = CompactionLock::try_acquire(&data_dir, "b")?.unwrap();
+    // 'c' is a merge input that was swapped out but never removed
+    std::fs::create_dir_all(data_dir.join("compacted").join("c"))?;
+
+    let mut abandoned = recover_abandoned_compactions(&data_dir, &db)?;
+    assert!(!data_dir.join("compacted").join("c").exists());
+    assert_eq!(abandoned.len(), 1);
+    let (name, lock) = abandoned.pop().unwrap();
+    assert_eq!(name, "a");
+    assert!(CompactionLock::try_acquire(&data_dir, "a")?.is_none());
+
+    let tmp = prepare_compaction(&data_dir, &db, &name)?.unwrap();
+    assert!(!tmp.exists());
+    let (target, source) = (RecordId::new(1, 1, 1), RecordId::new(2, 2, 2));
+    CompactedStorageWriter::new(&tmp)?
+        .log_backlinks(&target, &std::collections::BTreeSet::from([source]))?;
+    finish_compaction(&data_dir, &db, &name, lock)?;
+
+    assert!(!tmp.exists());
+    let mut sources = std::collections::BTreeSet::new();
+    CompactedStorageReader::new(data_dir.join("compacted").join("a"))?
+        .read_backlinks(&target, &mut sources)?;
+    assert_eq!(sources.len(), 1);
+    let store_type: String = db.query_row(
+        "SELECT type FROM data_stores WHERE name = 'a' AND compaction_in_progress = 0",
+        (),
+        |row| row.get(0),
+    )?;
+    assert_eq!(store_type, "compacted");
+
+    // registered stores are left alone
+    recover_abandoned_compactions(&data_dir, &db)?;
+    assert!(data_dir.join("compacted").join("a").exists());
+
+    drop(b_lock);
+    Ok(())
+}
@@ -0,0 +1,442 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+};
+
+use crate::{data::record::RecordId, db::DbConnection, AppContext};
+use anyhow::Result;
+
+use super::{
+    compacted::CompactedStorageMutator,
+    live::{LiveStorageMutator, LiveStorageWriter, IDENTITY_DIR, REVERSE_DIR},
+    store::{BacklinkStore, StoreType},
+};
+
+// a delete gets applied to every store in `data_stores` right away. compactions and merges
+// copy their inputs into a store that isn't listed yet though, so they could miss a delete
+// that lands in an input after they've read that part of it. to catch those, every delete is
+// also recorded in `pending_deletes`, and whoever writes a new store notes in
+// `pending_delete_marks` how far along that table was before they started reading. everything
+// past the mark gets applied to the new store right before and right after it's published.
+//
+// a delete is recorded before it touches any store, and only flagged as applied once it has
+// reached all of them, so a mark is always taken below the first delete that is still being
+// applied. one that fails stays unapplied, and gets retried before the next delete.
+
+// a delete that keeps failing is given up on (loudly) after this many tries
+const MAX_DELETE_ATTEMPTS: i64 = 8;
+
+trait MarkDeleted {
+    fn mark_deleted(&mut self, target: &RecordId, source: &RecordId) -> Result<bool>;
+}
+
+impl MarkDeleted for LiveStorageMutator {
+    fn mark_deleted(&mut self, target: &RecordId, source: &RecordId) -> Result<bool> {
+        LiveStorageMutator::mark_deleted(self, target, source)
+    }
+}
+
+impl MarkDeleted for CompactedStorageMutator {
+    fn mark_deleted(&mut self, target: &RecordId, source: &RecordId) -> Result<bool> {
+        CompactedStorageMutator::mark_deleted(self, target, source)
+    }
+}
+
+type OpenMutator = fn(&Path) -> Result<Box<dyn MarkDeleted>>;
+
+fn open_live_mutator(dir: &Path) -> Result<Box<dyn MarkDeleted>> {
+    Ok(Box::new(LiveStorageMutator::new(dir)?))
+}
+
+fn open_compacted_mutator(dir: &Path) -> Result<Box<dyn MarkDeleted>> {
+    Ok(Box::new(CompactedStorageMutator::new(dir)?))
+}
+
+/// everything it takes to delete sources from one store
+struct MutableStore {
+    dir: PathBuf,
+    reverse_reader: Box<dyn BacklinkStore>,
+    forward: Box<dyn MarkDeleted>,
+    reverse: Box<dyn MarkDeleted>,
+    // opened for the first identity link that gets deleted
+    identity: Option<Box<dyn MarkDeleted>>,
+    open_mutator: OpenMutator,
+}
+
+impl MutableStore {
+    /// returns `None` for stores that predate reverse indexing, since nothing can be found in them
+    fn open(dir: &Path, store_type: StoreType) -> Result<Option<Self>> {
+        let reverse_dir = dir.join(REVERSE_DIR);
+        if !reverse_dir.exists() {
+            // DeletionStores keeps the `None` around, so ingest only reports each store once
+            tracing::error!(
+                ?dir,
+                "store has no reverse index, so deletes can't remove any of its backlinks"
+            );
+            return Ok(None);
+        }
+
+        let open_mutator: OpenMutator = match store_type {
+            StoreType::Live => open_live_mutator,
+            StoreType::Compacted => open_compacted_mutator,
+        };
+        Ok(Some(Self {
+            dir: dir.to_path_buf(),
+            reverse_reader: store_type.open_reader(&reverse_dir)?,
+            forward: open_mutator(dir)?,
+            reverse: open_mutator(&reverse_dir)?,
+            identity: None,
+            open_mutator,
+        }))
+    }
+
+    fn delete_source(&mut self, source: &RecordId) -> Result<usize> {
+        let mut targets = BTreeSet::new();
+        self.reverse_reader.read_backlinks(source, &mut targets)?;
+
+        let mut deleted = 0;
+        for target in targets {
+            let store = match (target.is_identity(), &mut self.identity) {
+                (true, Some(identity)) => identity,
+                (true, None) => self
+                    .identity
+                    .insert((self.open_mutator)(&self.dir.join(IDENTITY_DIR))?),
+                (false, _) => &mut self.forward,
+            };
+            if store.mark_deleted(&target, source)? {
+                deleted += 1;
+            }
+            self.reverse.mark_deleted(source, &target)?;
+        }
+
+        Ok(deleted)
+    }
+}
+
+/// the stores that deletes get applied to, kept open from one delete to the next
+#[derive(Default)]
+pub struct DeletionStores {
+    // by (id, name, type) in data_stores, so a store that gets compacted is opened again.
+    // `None` for stores that nothing can be deleted from
+    open: HashMap<(u64, String, String), Option<MutableStore>>,
+}
+
+impl DeletionStores {
+    /// removes every backlink that `source` created, across all data stores.
+    /// returns the number of backlinks that were removed
+    pub fn delete_source(
+        &mut self,
+        db: &DbConnection,
+        data_dir: &Path,
+        storage: &mut LiveStorageWriter,
+        source: &RecordId,
+    ) -> Result<usize> {
+        self.retry_failed(db, data_dir, storage)?;
+
+        db.execute(
+            "INSERT INTO pending_deletes (did, collection, rkey) VALUES (?, ?, ?)",
+            (source.did as i64, source.collection, source.rkey as i64),
+        )?;
+        self.apply(db, data_dir, storage, db.last_insert_rowid(), source)
+    }
+
+    // deletes that didn't make it to every store, including ones that another process is
+    // still busy with. applying one twice doesn't hurt
+    fn retry_failed(
+        &mut self,
+        db: &DbConnection,
+        data_dir: &Path,
+        storage: &mut LiveStorageWriter,
+    ) -> Result<()> {
+        let rows = {
+            let mut statement = db.prepare_cached(
+                "SELECT id, did, collection, rkey, attempts FROM pending_deletes WHERE applied = 0 AND attempts < ? ORDER BY id ASC LIMIT 64",
+            )?;
+            let rows = statement
+                .query_map([MAX_DELETE_ATTEMPTS], |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        pending_delete_source(row)?,
+                        row.get::<_, i64>(4)?,
+                    ))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            rows
+        };
+
+        for (id, source, attempts) in rows {
+            db.execute(
+                "UPDATE pending_deletes SET attempts = attempts + 1 WHERE id = ?",
+                [id],
+            )?;
+            if let Err(e) = self.apply(db, data_dir, storage, id, &source) {
+                if attempts + 1 >= MAX_DELETE_ATTEMPTS {
+                    tracing::error!(
+                        ?source,
+                        "giving up on a delete after {MAX_DELETE_ATTEMPTS} attempts: {e:?}"
+                    );
+                } else {
+                    tracing::warn!(?source, "retrying a delete failed again: {e:?}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(
+        &mut self,
+        db: &DbConnection,
+        data_dir: &Path,
+        storage: &mut LiveStorageWriter,
+        id: i64,
+        source: &RecordId,
+    ) -> Result<usize> {
+        let mut deleted = storage.delete_source(source)?;
+
+        let rows = {
+            let mut statement =
+                db.prepare_cached("SELECT id, name, type FROM data_stores ORDER BY id ASC")?;
+            let rows = statement
+                .query_map((), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+                .collect::<rusqlite::Result<Vec<(u64, String, String)>>>()?;
+            rows
+        };
+        self.open.retain(|key, _| rows.contains(key));
+
+        for key in rows {
+            let (_, name, store_type) = &key;
+            let store_type = StoreType::parse(store_type)?;
+            let store_dir = store_type.dir(data_dir, name);
+            if store_dir == storage.dir() {
+                continue;
+            }
+
+            let store = match self.open.get_mut(&key) {
+                Some(store) => store,
+                None => {
+                    let store = MutableStore::open(&store_dir, store_type)?;
+                    self.open.entry(key.clone()).or_insert(store)
+                }
+            };
+            if let Some(store) = store {
+                match store.delete_source(source) {
+                    Ok(n) => deleted += n,
+                    Err(e) => {
+                        // opened again for the retry, in case that is what went wrong
+                        self.open.remove(&key);
+                        return Err(e);
+                    }
+                }
+            }
+        }
+
+        db.execute("UPDATE pending_deletes SET applied = 1 WHERE id = ?", [id])?;
+        Ok(deleted)
+    }
+}
+
+fn pending_delete_source(row: &rusqlite::Row) -> rusqlite::Result<RecordId> {
+    Ok(RecordId::new(
+        row.get::<_, i64>(1)? as u64,
+        row.get(2)?,
+        row.get::<_, i64>(3)? as u64,
+    ))
+}
+
+/// removes every backlink that `source` created, across all data stores.
+/// returns the number of backlinks that were removed
+pub fn delete_source(
+    app: &mut AppContext,
+    storage: &mut LiveStorageWriter,
+    source: &RecordId,
+) -> Result<usize> {
+    app.deletion_stores
+        .delete_source(&app.db, &app.data_dir, storage, source)
+}
+
+/// notes how far along `pending_deletes` is, for a compaction or merge into `name` that is
+/// about to start reading its inputs. a mark left behind by an earlier attempt is kept
+pub fn mark_pending_deletes(db: &DbConnection, name: &str) -> Result<()> {
+    db.execute(
+        "INSERT OR IGNORE INTO pending_delete_marks (name, mark) SELECT ?1, COALESCE(
+            (SELECT MIN(id) - 1 FROM pending_deletes WHERE applied = 0 AND attempts < ?2),
+            (SELECT MAX(id) FROM pending_deletes),
+            0
+        )",
+        (name, MAX_DELETE_ATTEMPTS),
+    )?;
+    Ok(())
+}
+
+/// applies every delete past `name`'s mark to the compacted store at `dir`,
+/// and moves the mark along. returns the number of backlinks that were removed
+pub fn apply_pending_deletes(db: &DbConnection, name: &str, dir: &Path) -> Result<usize> {
+    let mark = match db.query_row(
+        "SELECT mark FROM pending_delete_marks WHERE name = ?",
+        [name],
+        |row| row.get::<_, i64>(0),
+    ) {
+        Ok(mark) => mark,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            tracing::warn!(
+                name,
+                "no pending deletes mark, deletes made while it was being written may be missing"
+            );
+            return Ok(0);
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut statement = db.prepare(
+        "SELECT id, did, collection, rkey FROM pending_deletes WHERE id > ? ORDER BY id ASC",
+    )?;
+    let rows = statement
+        .query_map([mark], |row| {
+            Ok((row.get::<_, i64>(0)?, pending_delete_source(row)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let Some(&(last, _)) = rows.last() else {
+        return Ok(0);
+    };
+
+    let mut deleted = 0;
+    if let Some(mut store) = MutableStore::open(dir, StoreType::Compacted)? {
+        for (_, source) in rows {
+            deleted += store.delete_source(&source)?;
+        }
+    }
+    db.execute(
+        "UPDATE pending_delete_marks SET mark = ? WHERE name = ?",
+        (last, name),
+    )?;
+    Ok(deleted)
+}
+
+/// drops `name`'s mark once its store is published and caught up (or given up on),
+/// along with every pending delete that no other mark still needs
+pub fn clear_pending_deletes_mark(db: &DbConnection, name: &str) -> Result<()> {
+    db.execute("DELETE FROM pending_delete_marks WHERE name = ?", [name])?;
+    db.execute(
+        "DELETE FROM pending_deletes WHERE (applied = 1 OR attempts >= ?)
+            AND id <= COALESCE((SELECT MIN(mark) FROM pending_delete_marks), (SELECT MAX(id) FROM pending_deletes))",
+        [MAX_DELETE_ATTEMPTS],
+    )?;
+    Ok(())
+}
+
+#[test]
+fn test_pending_deletes() -> Result<()> {
+    use super::compacted::{CompactedStorageReader, CompactedStorageWriter};
+
+    let dir = crate::test_util::TempDir::new();
+    let db = rusqlite::Connection::open_in_memory()?;
+    crate::db::setup_db(&db)?;
+
+    // a store being written while sources get deleted: one that was done before it started
+    // reading, one that was still being applied, and one that only came in after
+    let (target, early, busy, late, kept) = (
+        RecordId::new(1, 1, 1),
+        RecordId::new(2, 2, 2),
+        RecordId::new(3, 2, 3),
+        RecordId::new(4, 2, 4),
+        RecordId::new(5, 2, 5),
+    );
+    let insert = |source: &RecordId, applied: bool| {
+        db.execute(
+            "INSERT INTO pending_deletes (did, collection, rkey, applied) VALUES (?, ?, ?, ?)",
+            (
+                source.did as i64,
+                source.collection,
+                source.rkey as i64,
+                applied,
+            ),
+        )
+    };
+    insert(&early, true)?;
+    insert(&busy, false)?;
+    mark_pending_deletes(&db, "a")?;
+    insert(&late, true)?;
+
+    let sources = BTreeSet::from([early, busy, late, kept]);
+    CompactedStorageWriter::new(&dir)?.log_backlinks(&target, &sources)?;
+    let mut reverse = CompactedStorageWriter::new(dir.join(REVERSE_DIR))?;
+    for source in &sources {
+        reverse.log_backlinks(source, &BTreeSet::from([target]))?;
+    }
+    drop(reverse);
+
+    // only the deletes past the mark are applied, and applying again is a no-op
+    assert_eq!(apply_pending_deletes(&db, "a", &dir)?, 2);
+    assert_eq!(apply_pending_deletes(&db, "a", &dir)?, 0);
+    let mut found = BTreeSet::new();
+    CompactedStorageReader::new(&dir)?.read_backlinks(&target, &mut found)?;
+    assert_eq!(found, BTreeSet::from([early, kept]));
+
+    // another mark still needs the later deletes, and unapplied ones are never dropped
+    db.execute("UPDATE pending_deletes SET applied = 1", ())?;
+    mark_pending_deletes(&db, "b")?;
+    insert(&kept, false)?;
+    clear_pending_deletes_mark(&db, "a")?;
+    let count = |db: &DbConnection| -> Result<i64> {
+        Ok(db.query_row("SELECT COUNT(*) FROM pending_deletes", (), |row| row.get(0))?)
+    };
+    assert_eq!(count(&db)?, 1);
+    clear_pending_deletes_mark(&db, "b")?;
+    assert_eq!(count(&db)?, 1);
+    db.execute("UPDATE pending_deletes SET applied = 1", ())?;
+    clear_pending_deletes_mark(&db, "b")?;
+    assert_eq!(count(&db)?, 0);
+
+    Ok(())
+}
+
+#[test]
+fn test_failed_delete_is_retried() -> Result<()> {
+    use super::compacted::{CompactedStorageReader, CompactedStorageWriter};
+
+    let data_dir = crate::test_util::TempDir::new();
+    let db = rusqlite::Connection::open_in_memory()?;
+    crate::db::setup_db(&db)?;
+    let (target, source, other) = (
+        RecordId::new(1, 1, 1),
+        RecordId::new(2, 2, 2),
+        RecordId::new(3, 2, 3),
+    );
+
+    let mut storage = LiveStorageWriter::new(data_dir.join("live").join("a"))?;
+    storage.log_backlink(&target, &other)?;
+    let compacted = data_dir.join("compacted").join("b");
+    CompactedStorageWriter::new(&compacted)?
+        .log_backlinks(&target, &BTreeSet::from([source, other]))?;
+    CompactedStorageWriter::new(compacted.join(REVERSE_DIR))?
+        .log_backlinks(&source, &BTreeSet::from([target]))?;
+    // a store that can't be opened, so deletes fail
+    std::fs::create_dir_all(data_dir.join("compacted").join("c").join(REVERSE_DIR))?;
+    db.execute(
+        "INSERT INTO data_stores (name, type) VALUES ('a', 'live'), ('b', 'compacted'), ('c', 'compacted')",
+        (),
+    )?;
+
+    let mut stores = DeletionStores::default();
+    assert!(stores
+        .delete_source(&db, &data_dir, &mut storage, &source)
+        .is_err());
+    let applied: bool =
+        db.query_row("SELECT applied FROM pending_deletes", (), |row| row.get(0))?;
+    assert!(!applied);
+
+    // once the broken store is gone, the next delete brings the failed one along
+    db.execute("DELETE FROM data_stores WHERE name = 'c'", ())?;
+    stores.delete_source(&db, &data_dir, &mut storage, &other)?;
+    let unapplied: i64 = db.query_row(
+        "SELECT COUNT(*) FROM pending_deletes WHERE applied = 0",
+        (),
+        |row| row.get(0),
+    )?;
+    assert_eq!(unapplied, 0);
+    let mut found = BTreeSet::new();
+    CompactedStorageReader::new(&compacted)?.read_backlinks(&target, &mut found)?;
+    assert_eq!(found, BTreeSet::from([other]));
+
+    Ok(())
+}
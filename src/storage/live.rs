@@ -2,31 +2,48 @@
 #![allow(deprecated)]
 
 use std::{
-    collections::{BTreeMap, BTreeSet},
+    collections::{BTreeMap, BTreeSet, HashMap},
     fs::File,
     io::{Seek, SeekFrom, Write},
-    mem::size_of,
+    mem::{offset_of, size_of},
     os::fd::AsRawFd,
-    path::Path,
+    path::{Path, PathBuf},
 };
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use nix::fcntl::{flock, FlockArg};
-use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
+use zerocopy::{FromBytes, FromZeros, Immutable, IntoBytes, KnownLayout};
 
-use crate::data::{record::RecordId, Padding};
+use crate::data::{
+    cid::CidHash,
+    record::{RecordId, RECORD_FLAG_DELETED, RECORD_FLAG_FAR_PREV, RECORD_FLAG_JUMP},
+    Padding,
+};
 
-use super::{pread_all, pwrite_all};
+use super::{live_hash::LiveHashIndex, pread_all, pwrite_all, FormatInfo};
 
 #[derive(Debug, Clone, Copy, KnownLayout, IntoBytes, FromBytes)]
+#[repr(C)]
 pub struct IndexHeader {
     pub num_records: u64,
-    _pad: Padding<56>,
+    // 1 + index of the target whose chain is in the middle of being changed (0 if none).
+    // if a writer dies while this is set, the next one to take the lock repairs that chain
+    pub pending: u64,
+    pub format: FormatInfo,
+    _pad: Padding<32>,
 }
-const INDEX_HEADER_SIZE: usize = size_of::<IndexHeader>();
+pub(crate) const INDEX_HEADER_SIZE: usize = size_of::<IndexHeader>();
 // assert IndexHeader is 64 bytes
 const _: [(); 64] = [(); INDEX_HEADER_SIZE];
 
+pub const LIVE_MAGIC: [u8; 4] = *b"BSLV";
+// 0: from before headers had a format, otherwise the same as 1
+// 1: IndexHeader has a format
+pub const LIVE_VERSION: u32 = 1;
+// some chains contain jump entries (see BacklinkEntry::is_jump)
+pub const LIVE_FLAG_LONG_JUMPS: u64 = 1 << 0;
+pub const LIVE_FLAGS: u64 = LIVE_FLAG_LONG_JUMPS;
+
 #[derive(Debug, Clone, Copy, KnownLayout, IntoBytes, FromBytes)]
 #[repr(C, packed)]
 pub struct RecordIndexEntry {
@@ -50,24 +67,259 @@ pub struct IndexValue {
 #[derive(Debug, Clone, Copy, IntoBytes, FromBytes)]
 #[repr(C, packed)]
 pub struct BacklinkEntry {
+    // with the backlink's LinkPath in its flags (see RecordId::path)
     pub source: RecordId,
     pub next: i32, // relative offset in backlinks to BacklinkEntry (0 if null)
     pub prev: i32,
 }
 const BACKLINK_ENTRY_SIZE: usize = size_of::<BacklinkEntry>();
+
+impl BacklinkEntry {
+    /// jump entries don't hold a backlink. when the next entry of a chain ends up too far away
+    /// for `next`, its tail gets turned into one of these, with the absolute slot of the next
+    /// entry in `source.rkey`, and its backlink moves along to sit right before the new entry
+    pub fn is_jump(&self) -> bool {
+        self.source._flags.0 & RECORD_FLAG_JUMP != 0
+    }
+
+    /// whether `prev` can't be trusted, because the previous entry is too far away
+    pub fn has_far_prev(&self) -> bool {
+        self.source._flags.0 & RECORD_FLAG_FAR_PREV != 0
+    }
+
+    pub fn next_slot(&self, slot: u64) -> Option<u64> {
+        match self.is_jump() {
+            true => Some(self.source.rkey),
+            false => relative_slot(slot, self.next),
+        }
+    }
+}
+
 // assert BacklinkEntry is 32 bytes
 const _: [(); 32] = [(); BACKLINK_ENTRY_SIZE];
+const FLAGS_OFFSET: usize = offset_of!(BacklinkEntry, source) + offset_of!(RecordId, _flags);
+const NEXT_OFFSET: usize = offset_of!(BacklinkEntry, next);
+const PREV_OFFSET: usize = offset_of!(BacklinkEntry, prev);
+const PENDING_OFFSET: usize = offset_of!(IndexHeader, pending);
+
+// every live store keeps a second set of chains in this subdirectory with targets and sources
+// swapped, so that we can find out what a record linked to once it has been deleted
+pub const REVERSE_DIR: &str = "reverse";
+// and record-to-identity links (e.g. a follow's `subject`) in this one, as chains of their own
+// whose targets are RecordId::identity(did). the reverse chains cover them too
+pub const IDENTITY_DIR: &str = "identity";
+// everything that gets compacted, merged and checked along with a store
+pub const SUBSTORE_DIRS: [&str; 2] = [REVERSE_DIR, IDENTITY_DIR];
+
+// stores that record cids keep them next to links.dat, as one CidHash per slot.
+// it only gets written for slots that have a cid, so it can be shorter than links.dat,
+// and everything past its end has none
+pub const CIDS_FILE: &str = "cids.dat";
+const CID_SIZE: usize = size_of::<CidHash>();
+
+fn read_cid(cids: &File, cids_len: u64, slot: u64) -> Result<CidHash> {
+    let pos = usize::try_from(slot).unwrap() * CID_SIZE;
+    if pos as u64 + CID_SIZE as u64 > cids_len {
+        return Ok(0);
+    }
+    let mut buf = [0u8; CID_SIZE];
+    pread_all(cids, &mut buf, pos)?;
+    Ok(CidHash::from_le_bytes(buf))
+}
+
+pub(crate) fn count_index_entries(index: &File) -> Result<u64> {
+    let len = index.metadata()?.len() as usize;
+    Ok((len.saturating_sub(INDEX_HEADER_SIZE) / INDEX_ENTRY_SIZE) as u64)
+}
+
+pub(crate) fn read_index_entry(index: &File, idx: u64) -> Result<RecordIndexEntry> {
+    let mut buf = [0u8; INDEX_ENTRY_SIZE];
+    pread_all(
+        index,
+        &mut buf,
+        INDEX_HEADER_SIZE + usize::try_from(idx).unwrap() * INDEX_ENTRY_SIZE,
+    )?;
+    Ok(zerocopy::transmute!(buf))
+}
+
+/// reads a chunk of consecutive index entries starting at `start` (and stopping before `end`)
+pub(crate) fn read_index_entries(
+    index: &File,
+    start: u64,
+    end: u64,
+) -> Result<Vec<RecordIndexEntry>> {
+    let count = (end - start).min(4096) as usize;
+    let mut entries = RecordIndexEntry::new_vec_zeroed(count).unwrap();
+    pread_all(
+        index,
+        entries.as_mut_bytes(),
+        INDEX_HEADER_SIZE + usize::try_from(start).unwrap() * INDEX_ENTRY_SIZE,
+    )?;
+    Ok(entries)
+}
+
+fn read_backlink_entry(links: &File, slot: u64) -> Result<BacklinkEntry> {
+    let pos = usize::try_from(slot).unwrap() * BACKLINK_ENTRY_SIZE;
+    let mut buf = [0u8; BACKLINK_ENTRY_SIZE];
+    pread_all(links, &mut buf, pos)?;
+    Ok(zerocopy::transmute!(buf))
+}
+
+fn relative_slot(slot: u64, offset: i32) -> Option<u64> {
+    (offset != 0).then(|| slot.checked_add_signed(offset as i64).unwrap())
+}
+
+// we only ever write the flags so that a concurrent reader can't see a torn entry
+fn mark_deleted_in_chain(links: &File, head: u64, source: &RecordId) -> Result<bool> {
+    if head == u64::MAX {
+        return Ok(false);
+    }
+
+    let mut marked = false;
+    let mut slot = head;
+    loop {
+        let entry = read_backlink_entry(links, slot)?;
+        if !entry.is_jump() && &entry.source == source && !entry.source.is_deleted() {
+            let flags: u32 = entry.source._flags.0 | RECORD_FLAG_DELETED;
+            let pos = usize::try_from(slot).unwrap() * BACKLINK_ENTRY_SIZE;
+            pwrite_all(links, flags.as_bytes(), pos + FLAGS_OFFSET)?;
+            marked = true;
+        }
+        let Some(next_slot) = entry.next_slot(slot) else {
+            break;
+        };
+        slot = next_slot;
+    }
+
+    Ok(marked)
+}
+
+fn read_index_header(index: &File) -> Result<IndexHeader> {
+    let mut buf = [0u8; INDEX_HEADER_SIZE];
+    pread_all(index, &mut buf, 0)?;
+    Ok(zerocopy::transmute!(buf))
+}
+
+fn check_format(index: &File) -> Result<()> {
+    read_index_header(index)?
+        .format
+        .check(LIVE_MAGIC, LIVE_VERSION, LIVE_FLAGS)?;
+    Ok(())
+}
+
+fn set_pending(index: &File, idx: Option<u64>) -> Result<()> {
+    let pending = idx.map_or(0, |idx| idx + 1);
+    pwrite_all(index, pending.as_bytes(), PENDING_OFFSET)
+}
+
+// writes are ordered so that a chain can always be walked from its head:
+// a new entry is fully written before anything points at it, and a removed entry keeps
+// its own pointers. so after a crash, the chain reachable from the head is the truth,
+// and only the `prev` pointers and the index entry's `tail` can be stale.
+// (this relies on the kernel not tearing a single small pwrite, and on it having
+// made it to the page cache, i.e. it covers the process dying, not the machine)
+fn repair_chain(index: &File, links: &File, idx: u64) -> Result<()> {
+    let mut index_entry = read_index_entry(index, idx)?;
+    let num_records = read_index_header(index)?.num_records;
+
+    let mut tail = u64::MAX;
+    let mut slot = index_entry.head;
+    let mut steps = 0;
+    while slot != u64::MAX && slot < num_records && steps < num_records {
+        let entry = read_backlink_entry(links, slot)?;
+        let pos = usize::try_from(slot).unwrap() * BACKLINK_ENTRY_SIZE;
+        let prev = match tail {
+            u64::MAX => Ok(0),
+            tail => i32::try_from(tail as i64 - slot as i64),
+        };
+        match prev {
+            Ok(prev) if entry.prev != prev => {
+                pwrite_all(links, prev.as_bytes(), pos + PREV_OFFSET)?;
+            }
+            Err(_) if !entry.has_far_prev() => {
+                let flags: u32 = entry.source._flags.0 | RECORD_FLAG_FAR_PREV;
+                pwrite_all(links, flags.as_bytes(), pos + FLAGS_OFFSET)?;
+            }
+            _ => {}
+        }
+
+        tail = slot;
+        steps += 1;
+        slot = entry.next_slot(slot).unwrap_or(u64::MAX);
+    }
+    if slot != u64::MAX {
+        // the last entry points somewhere it shouldn't, so cut the chain there
+        let pos = usize::try_from(tail).unwrap() * BACKLINK_ENTRY_SIZE;
+        pwrite_all(links, 0i32.as_bytes(), pos + NEXT_OFFSET)?;
+    }
+
+    if tail == u64::MAX {
+        index_entry.head = u64::MAX;
+    }
+    index_entry.tail = tail;
+    pwrite_all(
+        index,
+        index_entry.as_mut_bytes(),
+        INDEX_HEADER_SIZE + usize::try_from(idx).unwrap() * INDEX_ENTRY_SIZE,
+    )?;
+    Ok(())
+}
+
+/// brings a live store back to a consistent state after a writer died halfway through a write.
+/// must be called with the index.dat lock held. returns whether anything had to be fixed
+pub fn recover(index: &File, links: &File) -> Result<bool> {
+    let mut recovered = false;
+
+    // a torn append of an index entry
+    let len = index.metadata()?.len();
+    let entries_len = len.saturating_sub(INDEX_HEADER_SIZE as u64);
+    if len > INDEX_HEADER_SIZE as u64 && entries_len % INDEX_ENTRY_SIZE as u64 != 0 {
+        index.set_len(len - entries_len % INDEX_ENTRY_SIZE as u64)?;
+        recovered = true;
+    }
+
+    // a torn (or never counted) append of a links entry. slots are written before
+    // num_records is bumped, so links.dat is normally never shorter than it says
+    let header = read_index_header(index)?;
+    let links_len = header.num_records * BACKLINK_ENTRY_SIZE as u64;
+    if links.metadata()?.len() != links_len {
+        links.set_len(links_len)?;
+        recovered = true;
+    }
+
+    let pending = header.pending;
+    if pending != 0 {
+        if pending - 1 < count_index_entries(index)? {
+            repair_chain(index, links, pending - 1)?;
+        }
+        set_pending(index, None)?;
+        recovered = true;
+    }
+
+    Ok(recovered)
+}
 
 pub struct LiveStorageWriter {
-    index_btree: BTreeMap<RecordId, IndexValue>,
+    dir: PathBuf,
+    hash: LiveHashIndex,
     index_file: File,        // create, write, read
     index_file_append: File, // append
     links_file: File,        // create, write, read
+    cids_file: Option<File>, // write, read (created with the first cid)
+    reverse: Option<Box<LiveStorageWriter>>,
+    identity: Option<Box<LiveStorageWriter>>,
 }
 
 impl LiveStorageWriter {
     pub fn new(dir: impl AsRef<Path>) -> Result<Self> {
-        let _ = std::fs::create_dir_all(&dir);
+        let mut writer = Self::open(dir.as_ref())?;
+        writer.reverse = Some(Box::new(Self::open(&dir.as_ref().join(REVERSE_DIR))?));
+        writer.identity = Some(Box::new(Self::open(&dir.as_ref().join(IDENTITY_DIR))?));
+        Ok(writer)
+    }
+
+    fn open(dir: &Path) -> Result<Self> {
+        let _ = std::fs::create_dir_all(dir);
 
         let base_options = File::options()
             .create(true)
@@ -76,71 +328,91 @@ impl LiveStorageWriter {
             .read(true)
             .clone();
 
-        let mut index_file = base_options.clone().open(dir.as_ref().join("index.dat"))?;
+        let mut index_file = base_options.clone().open(dir.join("index.dat"))?;
         let index_file_append = base_options
             .clone()
             .write(false)
             .append(true)
-            .open(dir.as_ref().join("index.dat"))?;
-        {
+            .open(dir.join("index.dat"))?;
+
+        let links_file = base_options.clone().open(dir.join("links.dat"))?;
+        let cids_file = match base_options.clone().create(false).open(dir.join(CIDS_FILE)) {
+            Ok(file) => Some(file),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        // other writers may be setting up the same store right now
+        let index_raw_fd = index_file.as_raw_fd();
+        flock(index_raw_fd, FlockArg::LockExclusive)?;
+        let hash = (|| {
+            // flags get set as the features they stand for are first used
+            let format = FormatInfo::new(LIVE_MAGIC, LIVE_VERSION, 0);
             let mut buf = [0u8; INDEX_HEADER_SIZE];
             if pread_all(&index_file, &mut buf, 0).is_err() {
                 let mut header = IndexHeader {
                     num_records: 0,
+                    pending: 0,
+                    format,
                     _pad: Default::default(),
                 };
                 pwrite_all(&mut index_file, header.as_mut_bytes(), 0)?;
             }
-        };
 
-        let index_btree = Self::load_btree(&mut index_file)?;
+            let mut header = read_index_header(&index_file)?;
+            let version = header
+                .format
+                .check(LIVE_MAGIC, LIVE_VERSION, LIVE_FLAGS)
+                .with_context(|| format!("can't write to live store {}", dir.display()))?;
+            if version < LIVE_VERSION {
+                // older versions only differ in their header
+                header.format = format;
+                pwrite_all(&index_file, header.as_mut_bytes(), 0)?;
+            }
+
+            if recover(&index_file, &links_file)? {
+                tracing::warn!(?dir, "recovered interrupted writes in live store");
+            }
 
-        let links_file = base_options.clone().open(dir.as_ref().join("links.dat"))?;
+            LiveHashIndex::open_for_writing(dir, &index_file)
+        })();
+        let _ = flock(index_raw_fd, FlockArg::Unlock);
+        let hash = hash?;
 
         Ok(Self {
+            dir: dir.to_path_buf(),
+            hash,
             index_file,
             index_file_append,
-            index_btree,
             links_file,
+            cids_file,
+            reverse: None,
+            identity: None,
         })
     }
 
-    fn load_btree(index_file: &mut File) -> Result<BTreeMap<RecordId, IndexValue>> {
-        // TODO: this should probably not be all in-memory but we ball for now
-
-        let mut map = BTreeMap::new();
-        index_file.seek(SeekFrom::Start(INDEX_HEADER_SIZE as u64))?;
-        let mut idx = 0;
-        loop {
-            let Ok(entry) = RecordIndexEntry::read_from_io(&mut *index_file) else {
-                break;
-            };
-            map.insert(
-                entry.target,
-                IndexValue {
-                    head: entry.head,
-                    tail: entry.tail,
-                    idx,
-                },
-            );
-            idx += 1;
-        }
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
 
-        Ok(map)
+    pub(crate) fn index(&self) -> &File {
+        &self.index_file
     }
 
-    fn find_in_index(&mut self, target: &RecordId) -> Result<IndexValue> {
-        let value = self
-            .index_btree
-            .get(target)
-            .ok_or(anyhow::anyhow!("not found"))?;
-        Ok(*value)
+    fn find_in_index(&mut self, target: &RecordId) -> Result<Option<IndexValue>> {
+        let Some((idx, entry)) = self.hash.find(&self.index_file, target)? else {
+            return Ok(None);
+        };
+        Ok(Some(IndexValue {
+            head: entry.head,
+            tail: entry.tail,
+            idx,
+        }))
     }
 
     fn update_index(&mut self, target: &RecordId, index_value: IndexValue) -> Result<()> {
         let index_entry_idx = usize::try_from(index_value.idx).unwrap();
 
-        self.index_btree.insert(*target, index_value);
         pwrite_all(
             &mut self.index_file,
             RecordIndexEntry {
@@ -155,7 +427,6 @@ impl LiveStorageWriter {
     }
 
     fn add_to_index(&mut self, target: &RecordId, index_value: IndexValue) -> Result<()> {
-        self.index_btree.insert(*target, index_value);
         self.index_file_append.write_all(
             RecordIndexEntry {
                 target: *target,
@@ -164,19 +435,39 @@ impl LiveStorageWriter {
             }
             .as_mut_bytes(),
         )?;
+        self.hash
+            .insert(&self.index_file, target, index_value.idx)?;
 
         Ok(())
     }
 
-    fn alloc_entry_slot(&mut self) -> Result<u64> {
+    /// runs `f` while holding the exclusive lock on index.dat.
+    ///
+    /// several processes (e.g. firehose-ingest and backfill) append to the same live store,
+    /// so every change to the index, the hash or a chain has to happen under this lock,
+    /// and nothing read from disk may be trusted across two calls
+    fn locked<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
         let index_raw_fd = self.index_file.as_raw_fd();
-        flock(index_raw_fd, FlockArg::LockExclusive).expect("failed to acquire index.dat lock");
+        flock(index_raw_fd, FlockArg::LockExclusive)?;
 
-        let mut header: IndexHeader = {
-            let mut buf = [0u8; INDEX_HEADER_SIZE];
-            pread_all(&self.index_file, &mut buf, 0)?;
-            zerocopy::transmute!(buf)
-        };
+        let result = (|| {
+            // another writer may have died while holding the lock
+            if recover(&self.index_file, &self.links_file)? {
+                tracing::warn!(dir = ?self.dir, "recovered interrupted writes in live store");
+            }
+            // someone else may have grown the hash table or added targets since we last looked
+            self.hash.refresh()?;
+            self.hash.catch_up(&self.index_file)?;
+            f(self)
+        })();
+
+        let _ = flock(index_raw_fd, FlockArg::Unlock);
+        result
+    }
+
+    // must be called with the index.dat lock held
+    fn alloc_entry_slot(&mut self) -> Result<u64> {
+        let mut header = read_index_header(&self.index_file)?;
         let cnt = header.num_records;
         header.num_records += 1;
         // allocate empty space at cnt
@@ -184,26 +475,81 @@ impl LiveStorageWriter {
         pwrite_all(&mut self.links_file, &[0u8; BACKLINK_ENTRY_SIZE], pos)?;
         pwrite_all(&mut self.index_file, header.as_mut_bytes(), 0)?;
 
-        let _ = flock(index_raw_fd, FlockArg::Unlock);
-
         Ok(cnt)
     }
 
     pub fn log_backlink(&mut self, target: &RecordId, source: &RecordId) -> Result<()> {
-        if let Ok(mut index_value) = self.find_in_index(target) {
+        self.log_backlink_with_cid(target, source, 0)
+    }
+
+    /// like `log_backlink`, but also records the hash of the cid that the link points at
+    /// (unless it's 0)
+    pub fn log_backlink_with_cid(
+        &mut self,
+        target: &RecordId,
+        source: &RecordId,
+        cid: CidHash,
+    ) -> Result<()> {
+        if let Some(reverse) = self.reverse.as_mut() {
+            // the path belongs to the backlink, not to the source as a target
+            reverse.log_backlink(&source.with_path(0), target)?;
+        }
+
+        self.locked(|this| {
+            let slot = this.log_backlink_locked(target, source)?;
+            match slot {
+                // written after the entry, so readers may briefly see it without its cid
+                Some(slot) if cid != 0 => this.write_cid(slot, cid),
+                _ => Ok(()),
+            }
+        })
+    }
+
+    /// logs a link from `source` to the identity `did` (rather than to one of its records)
+    pub fn log_identity_link(&mut self, did: u64, source: &RecordId) -> Result<()> {
+        let target = RecordId::identity(did);
+        if self.identity.is_none() {
+            anyhow::bail!("{} doesn't keep identity links", self.dir.display());
+        }
+        if let Some(reverse) = self.reverse.as_mut() {
+            reverse.log_backlink(&source.with_path(0), &target)?;
+        }
+        self.identity
+            .as_mut()
+            .unwrap()
+            .log_backlink(&target, source)
+    }
+
+    // must be called with the index.dat lock held
+    fn write_cid(&mut self, slot: u64, cid: CidHash) -> Result<()> {
+        let cids_file = match self.cids_file.as_mut() {
+            Some(cids_file) => cids_file,
+            None => self.cids_file.insert(
+                File::options()
+                    .create(true)
+                    .truncate(false)
+                    .write(true)
+                    .read(true)
+                    .open(self.dir.join(CIDS_FILE))?,
+            ),
+        };
+        let pos = usize::try_from(slot).unwrap() * CID_SIZE;
+        pwrite_all(cids_file, cid.as_bytes(), pos)
+    }
+
+    // returns the slot of the new entry, or None if it was a duplicate
+    fn log_backlink_locked(&mut self, target: &RecordId, source: &RecordId) -> Result<Option<u64>> {
+        if let Some(mut index_value) = self.find_in_index(target)? {
+            set_pending(&self.index_file, Some(index_value.idx))?;
             let mut tail_entry: Option<BacklinkEntry> = None;
             let tail_slot = index_value.tail;
-            let tail_pos = usize::try_from(tail_slot).unwrap() * BACKLINK_ENTRY_SIZE;
 
             if index_value.tail != u64::MAX {
-                let e: BacklinkEntry = {
-                    let mut buf = [0u8; BACKLINK_ENTRY_SIZE];
-                    pread_all(&self.links_file, &mut buf, tail_pos)?;
-                    zerocopy::transmute!(buf)
-                };
-                if &e.source == source {
+                let e = read_backlink_entry(&self.links_file, tail_slot)?;
+                if &e.source == source && !e.source.is_deleted() {
                     // we can cheaply avoid writing a duplicate entry here
-                    return Ok(());
+                    set_pending(&self.index_file, None)?;
+                    return Ok(None);
                 }
                 tail_entry.replace(e);
             }
@@ -213,29 +559,38 @@ impl LiveStorageWriter {
                 next: 0,
                 prev: 0,
             };
-            let slot = self.alloc_entry_slot()?;
+            let mut slot = self.alloc_entry_slot()?;
             let pos = usize::try_from(slot).unwrap() * BACKLINK_ENTRY_SIZE;
 
             if index_value.head == u64::MAX {
                 index_value.head = slot;
             }
 
-            // set prev to the previous end of the chain
-            if index_value.tail != u64::MAX {
-                new_entry.prev = (tail_slot as i64 - slot as i64).try_into().unwrap();
-            }
-            pwrite_all(&mut self.links_file, new_entry.as_mut_bytes(), pos)?;
+            match tail_entry {
+                None => pwrite_all(&mut self.links_file, new_entry.as_mut_bytes(), pos)?,
+                Some(tail_entry) => match i32::try_from(slot as i64 - tail_slot as i64) {
+                    Ok(next) => {
+                        // set prev to the previous end of the chain
+                        new_entry.prev = -next;
+                        pwrite_all(&mut self.links_file, new_entry.as_mut_bytes(), pos)?;
 
-            // update the 'next' at the end of the chain if we need to
-            if let Some(mut tail_entry) = tail_entry {
-                tail_entry.next = (slot as i64 - tail_slot as i64).try_into().unwrap();
-                pwrite_all(&mut self.links_file, tail_entry.as_mut_bytes(), tail_pos)?;
+                        // update the 'next' at the end of the chain
+                        // (only the 'next' field, so we don't clobber flags that someone else has set)
+                        let tail_pos = usize::try_from(tail_slot).unwrap() * BACKLINK_ENTRY_SIZE;
+                        pwrite_all(&self.links_file, next.as_bytes(), tail_pos + NEXT_OFFSET)?;
+                    }
+                    Err(_) => slot = self.jump_to(tail_slot, &tail_entry, slot, new_entry)?,
+                },
             }
 
             // update end of the chain
             index_value.tail = slot;
             self.update_index(target, index_value)?;
+            set_pending(&self.index_file, None)?;
+            Ok(Some(slot))
         } else {
+            // nothing points at the new entry until the index entry has been appended,
+            // so there's nothing to repair if we die before then
             let mut new_entry = BacklinkEntry {
                 source: *source,
                 next: 0,
@@ -249,22 +604,69 @@ impl LiveStorageWriter {
                 IndexValue {
                     head: slot,
                     tail: slot,
-                    idx: self.index_btree.len() as u64,
+                    idx: self.hash.num_entries()?,
                 },
             )?;
+            Ok(Some(slot))
+        }
+    }
+
+    /// links `new_entry` onto a chain whose tail is too far back to point at it:
+    /// the tail's backlink is copied to `moved_slot` (already allocated), `new_entry` goes
+    /// right after it, and the tail becomes a jump entry to the copy.
+    /// returns the slot that `new_entry` ended up in
+    fn jump_to(
+        &mut self,
+        tail_slot: u64,
+        tail_entry: &BacklinkEntry,
+        moved_slot: u64,
+        mut new_entry: BacklinkEntry,
+    ) -> Result<u64> {
+        let mut header = read_index_header(&self.index_file)?;
+        if header.format.flags & LIVE_FLAG_LONG_JUMPS == 0 {
+            // readers that don't know about jump entries have to stop reading this store now
+            header.format.flags |= LIVE_FLAG_LONG_JUMPS;
+            pwrite_all(&self.index_file, header.as_mut_bytes(), 0)?;
         }
 
-        Ok(())
+        let slot = self.alloc_entry_slot()?;
+        let mut moved_entry = BacklinkEntry {
+            source: tail_entry.source,
+            next: (slot - moved_slot) as i32,
+            prev: 0,
+        };
+        moved_entry.source._flags.0 |= RECORD_FLAG_FAR_PREV;
+        new_entry.prev = (moved_slot as i64 - slot as i64) as i32;
+        if let Some(cids_file) = &self.cids_file {
+            let cid = read_cid(cids_file, cids_file.metadata()?.len(), tail_slot)?;
+            if cid != 0 {
+                self.write_cid(moved_slot, cid)?;
+            }
+        }
+        let moved_pos = usize::try_from(moved_slot).unwrap() * BACKLINK_ENTRY_SIZE;
+        let pos = usize::try_from(slot).unwrap() * BACKLINK_ENTRY_SIZE;
+        pwrite_all(&self.links_file, moved_entry.as_mut_bytes(), moved_pos)?;
+        pwrite_all(&self.links_file, new_entry.as_mut_bytes(), pos)?;
+
+        // only now does anything point at the new entries.
+        // the tail keeps its `prev` (and whether that's far away)
+        let mut jump = RecordId::new(0, 0, moved_slot);
+        jump._flags.0 = RECORD_FLAG_JUMP | (tail_entry.source._flags.0 & RECORD_FLAG_FAR_PREV);
+        let tail_pos = usize::try_from(tail_slot).unwrap() * BACKLINK_ENTRY_SIZE;
+        pwrite_all(&self.links_file, jump.as_bytes(), tail_pos)?;
+
+        Ok(slot)
     }
 
     pub fn read_backlinks(&mut self, target: &RecordId) -> Result<Vec<BacklinkEntry>> {
-        let index_value = match self.find_in_index(target) {
-            Ok(i) => i,
-            Err(e) if e.to_string() == "not found" => return Ok(vec![]),
-            Err(e) => return Err(e),
+        let Some(index_value) = self.find_in_index(target)? else {
+            return Ok(vec![]);
         };
 
         let mut links = Vec::new();
+        if index_value.head == u64::MAX {
+            return Ok(links);
+        }
         let mut link_idx = index_value.head;
         loop {
             let link: BacklinkEntry = {
@@ -273,20 +675,152 @@ impl LiveStorageWriter {
                 pread_all(&self.links_file, &mut buf, pos)?;
                 zerocopy::transmute!(buf)
             };
-            links.push(link);
-            if link.next == 0 {
-                break;
+            if !link.is_jump() {
+                links.push(link);
             }
-            link_idx = link_idx.checked_add_signed(link.next as i64).unwrap();
+            let Some(next_idx) = link.next_slot(link_idx) else {
+                break;
+            };
+            link_idx = next_idx;
         }
 
         Ok(links)
     }
+
+    /// splices every `source` entry out of `target`'s chain, returning whether any were found.
+    /// removed entries are flagged as deleted and keep their own `next`/`prev`, so a concurrent
+    /// reader that is currently standing on one can still walk to the end of the chain
+    pub fn remove_backlink(&mut self, target: &RecordId, source: &RecordId) -> Result<bool> {
+        self.locked(|this| this.remove_backlink_locked(target, source))
+    }
+
+    fn remove_backlink_locked(&mut self, target: &RecordId, source: &RecordId) -> Result<bool> {
+        let Some(mut index_value) = self.find_in_index(target)? else {
+            return Ok(false);
+        };
+        if index_value.head == u64::MAX {
+            return Ok(false);
+        }
+
+        set_pending(&self.index_file, Some(index_value.idx))?;
+        let mut removed = false;
+        let mut slot = index_value.head;
+        loop {
+            let entry = read_backlink_entry(&self.links_file, slot)?;
+            if !entry.is_jump() && &entry.source == source {
+                self.unlink_entry(&mut index_value, slot, &entry)?;
+                removed = true;
+            }
+            let Some(next_slot) = entry.next_slot(slot) else {
+                break;
+            };
+            slot = next_slot;
+        }
+
+        if removed {
+            self.update_index(target, index_value)?;
+        }
+        set_pending(&self.index_file, None)?;
+
+        Ok(removed)
+    }
+
+    fn unlink_entry(
+        &mut self,
+        index_value: &mut IndexValue,
+        slot: u64,
+        entry: &BacklinkEntry,
+    ) -> Result<()> {
+        let pos = usize::try_from(slot).unwrap() * BACKLINK_ENTRY_SIZE;
+
+        // readers that have already reached this entry will skip it from now on
+        let flags: u32 = entry.source._flags.0 | RECORD_FLAG_DELETED;
+        pwrite_all(&self.links_file, flags.as_bytes(), pos + FLAGS_OFFSET)?;
+
+        if entry.has_far_prev() {
+            // we can't find the previous entry to point it past this one
+            return Ok(());
+        }
+        let prev_slot = relative_slot(slot, entry.prev);
+        let next_slot = relative_slot(slot, entry.next);
+        let (prev_to_next, next_to_prev) = match (prev_slot, next_slot) {
+            (Some(p), Some(n)) => match (
+                i32::try_from(n as i64 - p as i64),
+                i32::try_from(p as i64 - n as i64),
+            ) {
+                (Ok(prev_to_next), Ok(next_to_prev)) => (prev_to_next, next_to_prev),
+                // the neighbours are too far apart to point at each other,
+                // so leave the (flagged) entry in the chain
+                _ => return Ok(()),
+            },
+            _ => (0, 0),
+        };
+
+        match prev_slot {
+            Some(prev_slot) => {
+                let prev_pos = usize::try_from(prev_slot).unwrap() * BACKLINK_ENTRY_SIZE;
+                pwrite_all(
+                    &self.links_file,
+                    prev_to_next.as_bytes(),
+                    prev_pos + NEXT_OFFSET,
+                )?;
+            }
+            None => index_value.head = next_slot.unwrap_or(u64::MAX),
+        }
+        match next_slot {
+            Some(next_slot) => {
+                let next_pos = usize::try_from(next_slot).unwrap() * BACKLINK_ENTRY_SIZE;
+                pwrite_all(
+                    &self.links_file,
+                    next_to_prev.as_bytes(),
+                    next_pos + PREV_OFFSET,
+                )?;
+            }
+            None => index_value.tail = prev_slot.unwrap_or(u64::MAX),
+        }
+
+        Ok(())
+    }
+
+    /// deletes every backlink that `source` created in this store, returning how many were removed
+    pub fn delete_source(&mut self, source: &RecordId) -> Result<usize> {
+        let Some(reverse) = self.reverse.as_mut() else {
+            return Ok(0);
+        };
+        let targets = reverse.read_backlinks(source)?;
+
+        let mut deleted = 0;
+        for entry in targets {
+            if entry.source.is_deleted() {
+                continue;
+            }
+            let target = entry.source;
+            let removed = match (target.is_identity(), self.identity.as_mut()) {
+                (true, Some(identity)) => identity.remove_backlink(&target, source)?,
+                (true, None) => false,
+                (false, _) => self.remove_backlink(&target, source)?,
+            };
+            if removed {
+                deleted += 1;
+            }
+            if let Some(reverse) = self.reverse.as_mut() {
+                reverse.remove_backlink(source, &target)?;
+            }
+        }
+
+        Ok(deleted)
+    }
 }
 
 pub struct LiveStorageReader {
+    dir: PathBuf,
     index: File,
     links: File,
+    cids: Option<File>,
+    hash: Option<LiveHashIndex>,
+    // for stores without a hash.dat: target -> idx for the first `cached_entries` of index.dat
+    cache: HashMap<RecordId, u64>,
+    cached_entries: u64,
 }
 
 impl LiveStorageReader {
@@ -297,37 +831,81 @@ impl LiveStorageReader {
         let links = File::options()
             .read(true)
             .open(dir.as_ref().join("links.dat"))?;
-        Ok(Self { index, links })
+        check_format(&index)
+            .with_context(|| format!("can't read live store {}", dir.as_ref().display()))?;
+        // stores that nobody has opened for writing since hash.dat was introduced won't have one
+        let hash = LiveHashIndex::open(&dir).ok();
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+            index,
+            links,
+            cids: None,
+            hash,
+            cache: HashMap::new(),
+            cached_entries: 0,
+        })
+    }
+
+    pub(crate) fn index(&self) -> &File {
+        &self.index
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
     }
 
     pub fn list_all_targets(&mut self) -> Result<BTreeMap<RecordId, RecordIndexEntry>> {
         let mut tree = BTreeMap::new();
 
         self.index.seek(SeekFrom::Start(INDEX_HEADER_SIZE as u64))?;
-        loop {
-            let Ok(entry) = RecordIndexEntry::read_from_io(&mut self.index) else {
-                break;
-            };
+        while let Ok(entry) = RecordIndexEntry::read_from_io(&mut self.index) {
             tree.insert(entry.target, entry);
         }
 
         Ok(tree)
     }
 
+    pub fn find_index_entry(&mut self, target: &RecordId) -> Result<Option<RecordIndexEntry>> {
+        if self.hash.is_none() {
+            // a writer may have created one since we were opened
+            self.hash = LiveHashIndex::open(&self.dir).ok();
+        }
+
+        if let Some(hash) = self.hash.as_mut() {
+            if let Some((_idx, entry)) = hash.find(&self.index, target)? {
+                return Ok(Some(entry));
+            }
+            // the writer may have swapped in a bigger table that has newer targets in it
+            if hash.refresh()? {
+                return Ok(hash.find(&self.index, target)?.map(|(_idx, entry)| entry));
+            }
+            return Ok(None);
+        }
+
+        // no hash.dat, so we keep our own map and extend it with whatever has been appended
+        // to index.dat since the last lookup
+        let count = count_index_entries(&self.index)?;
+        while self.cached_entries < count {
+            for entry in read_index_entries(&self.index, self.cached_entries, count)? {
+                self.cache.insert(entry.target, self.cached_entries);
+                self.cached_entries += 1;
+            }
+        }
+
+        match self.cache.get(target) {
+            // re-read the entry since head and tail keep moving
+            Some(idx) => Ok(Some(read_index_entry(&self.index, *idx)?)),
+            None => Ok(None),
+        }
+    }
+
     pub fn read_backlinks(
         &mut self,
         target: &RecordId,
         backlinks: &mut BTreeSet<RecordId>,
     ) -> Result<()> {
-        self.index.seek(SeekFrom::Start(INDEX_HEADER_SIZE as u64))?;
-
-        let index_entry = loop {
-            let Ok(entry) = RecordIndexEntry::read_from_io(&mut self.index) else {
-                return Ok(());
-            };
-            if &entry.target == target {
-                break entry;
-            }
+        let Some(index_entry) = self.find_index_entry(target)? else {
+            return Ok(());
         };
 
         self.read_backlinks_from_index_entry(&index_entry, backlinks)?;
@@ -340,22 +918,494 @@ impl LiveStorageReader {
         index_entry: &RecordIndexEntry,
         backlinks: &mut BTreeSet<RecordId>,
     ) -> Result<()> {
+        self.read_chain(index_entry, backlinks, None)
+    }
+
+    /// like `read_backlinks`, but also adds the cid of every source that has one to `cids`
+    pub fn read_backlinks_with_cids(
+        &mut self,
+        target: &RecordId,
+        backlinks: &mut BTreeSet<RecordId>,
+        cids: &mut BTreeMap<RecordId, CidHash>,
+    ) -> Result<()> {
+        let Some(index_entry) = self.find_index_entry(target)? else {
+            return Ok(());
+        };
+
+        self.read_backlinks_with_cids_from_index_entry(&index_entry, backlinks, cids)
+    }
+
+    pub fn read_backlinks_with_cids_from_index_entry(
+        &mut self,
+        index_entry: &RecordIndexEntry,
+        backlinks: &mut BTreeSet<RecordId>,
+        cids: &mut BTreeMap<RecordId, CidHash>,
+    ) -> Result<()> {
+        if self.cids.is_none() {
+            // a writer may have created one since we last looked
+            self.cids = File::options()
+                .read(true)
+                .open(self.dir.join(CIDS_FILE))
+                .ok();
+        }
+        self.read_chain(index_entry, backlinks, Some(cids))
+    }
+
+    fn read_chain(
+        &self,
+        index_entry: &RecordIndexEntry,
+        backlinks: &mut BTreeSet<RecordId>,
+        mut cids: Option<&mut BTreeMap<RecordId, CidHash>>,
+    ) -> Result<()> {
+        if index_entry.head == u64::MAX {
+            return Ok(());
+        }
+        let cids_file = match (&cids, &self.cids) {
+            (Some(_), Some(cids_file)) => Some((cids_file, cids_file.metadata()?.len())),
+            _ => None,
+        };
+
         let mut slot = index_entry.head;
         loop {
-            let pos = slot as usize * BACKLINK_ENTRY_SIZE;
-            let entry: BacklinkEntry = {
-                let mut buf = [0u8; BACKLINK_ENTRY_SIZE];
-                pread_all(&self.links, &mut buf, pos)?;
-                zerocopy::transmute!(buf)
-            };
-            backlinks.insert(entry.source);
-            if entry.next == 0 {
-                break;
+            let entry = read_backlink_entry(&self.links, slot)?;
+            if !entry.is_jump() && !entry.source.is_deleted() {
+                backlinks.insert(entry.source);
+                if let (Some(cids), Some((cids_file, cids_len))) = (cids.as_mut(), cids_file) {
+                    let cid = read_cid(cids_file, cids_len, slot)?;
+                    if cid != 0 {
+                        cids.entry(entry.source).or_insert(cid);
+                    }
+                }
             }
+            let Some(next_slot) = entry.next_slot(slot) else {
+                break;
+            };
 
-            slot = (slot as i64 + entry.next as i64) as u64;
+            slot = next_slot;
         }
 
         Ok(())
     }
 }
+
+/// in-place edits to a live store that isn't ours to append to.
+/// we only flag entries here, which doesn't need the writers' index.dat lock
+pub struct LiveStorageMutator {
+    reader: LiveStorageReader,
+    links: File, // read, write
+}
+
+impl LiveStorageMutator {
+    pub fn new(dir: impl AsRef<Path>) -> Result<Self> {
+        let reader = LiveStorageReader::new(&dir)?;
+        let links = File::options()
+            .read(true)
+            .write(true)
+            .open(dir.as_ref().join("links.dat"))?;
+        Ok(Self { reader, links })
+    }
+
+    pub fn mark_deleted(&mut self, target: &RecordId, source: &RecordId) -> Result<bool> {
+        let Some(index_entry) = self.reader.find_index_entry(target)? else {
+            return Ok(false);
+        };
+        mark_deleted_in_chain(&self.links, index_entry.head, source)
+    }
+}
+
+/// checks a live store for damage, returning a description of every problem found.
+/// `on_record` sees every target and source, e.g. to check that their ids resolve.
+/// writers are locked out while this runs, so it sees a consistent store
+pub fn fsck(dir: impl AsRef<Path>, mut on_record: impl FnMut(&RecordId)) -> Result<Vec<String>> {
+    let index = File::options()
+        .read(true)
+        .open(dir.as_ref().join("index.dat"))?;
+    let links = File::options()
+        .read(true)
+        .open(dir.as_ref().join("links.dat"))?;
+
+    flock(index.as_raw_fd(), FlockArg::LockShared)?;
+    let result = fsck_locked(&index, &links, &mut on_record);
+    let _ = flock(index.as_raw_fd(), FlockArg::Unlock);
+    result
+}
+
+fn fsck_locked(
+    index: &File,
+    links: &File,
+    on_record: &mut impl FnMut(&RecordId),
+) -> Result<Vec<String>> {
+    let mut problems = Vec::new();
+
+    let header = read_index_header(index)?;
+    if let Err(e) = header.format.check(LIVE_MAGIC, LIVE_VERSION, LIVE_FLAGS) {
+        problems.push(format!("{e}"));
+        return Ok(problems);
+    }
+    let index_len = index.metadata()?.len() as usize;
+    if !(index_len - INDEX_HEADER_SIZE).is_multiple_of(INDEX_ENTRY_SIZE) {
+        problems.push(format!(
+            "index.dat has a partial entry at the end ({index_len} bytes)"
+        ));
+    }
+    let links_len = links.metadata()?.len();
+    if links_len != header.num_records * BACKLINK_ENTRY_SIZE as u64 {
+        problems.push(format!(
+            "num_records is {} but links.dat is {links_len} bytes long",
+            header.num_records
+        ));
+    }
+    if header.pending != 0 {
+        problems.push(format!(
+            "a write to index entry {} was interrupted and hasn't been recovered yet",
+            header.pending - 1
+        ));
+    }
+
+    let num_slots = header
+        .num_records
+        .min(links_len / BACKLINK_ENTRY_SIZE as u64);
+    let count = count_index_entries(index)?;
+    let mut idx = 0;
+    while idx < count {
+        for entry in read_index_entries(index, idx, count)? {
+            on_record(&entry.target);
+            if let Some(problem) =
+                fsck_chain(links, header.format.flags, &entry, num_slots, on_record)?
+            {
+                problems.push(format!("index entry {idx} ({:?}): {problem}", entry.target));
+            }
+            idx += 1;
+        }
+    }
+
+    Ok(problems)
+}
+
+fn fsck_chain(
+    links: &File,
+    header_flags: u64,
+    index_entry: &RecordIndexEntry,
+    num_slots: u64,
+    on_record: &mut impl FnMut(&RecordId),
+) -> Result<Option<String>> {
+    let (head, tail) = (index_entry.head, index_entry.tail);
+    if head == u64::MAX || tail == u64::MAX {
+        return Ok((head != tail).then(|| format!("head is {head} but tail is {tail}")));
+    }
+
+    let mut prev_slot = None;
+    let mut slot = head;
+    // a chain can't be longer than the store, so anything longer must be a cycle
+    for _ in 0..num_slots {
+        if slot >= num_slots {
+            return Ok(Some(format!("slot {slot} is out of bounds")));
+        }
+        let entry = read_backlink_entry(links, slot)?;
+        if !entry.is_jump() {
+            on_record(&entry.source);
+        }
+
+        let (prev, next) = (entry.prev, entry.next);
+        let expected_prev = prev_slot.map_or(0, |prev_slot| prev_slot as i64 - slot as i64);
+        if prev as i64 != expected_prev && !entry.has_far_prev() {
+            return Ok(Some(format!(
+                "slot {slot} has prev {prev} instead of {expected_prev}"
+            )));
+        }
+
+        if entry.is_jump() && header_flags & LIVE_FLAG_LONG_JUMPS == 0 {
+            return Ok(Some(format!(
+                "slot {slot} is a jump entry, but the header doesn't say there are any"
+            )));
+        }
+        if !entry.is_jump() && next == 0 {
+            return Ok((slot != tail).then(|| format!("chain ends at {slot} instead of {tail}")));
+        }
+        let Some(next_slot) = (match entry.is_jump() {
+            true => Some(entry.source.rkey),
+            false => slot.checked_add_signed(next as i64),
+        }) else {
+            return Ok(Some(format!(
+                "slot {slot} has next {next} which is out of bounds"
+            )));
+        };
+        prev_slot = Some(slot);
+        slot = next_slot;
+    }
+
+    Ok(Some("chain has a cycle".to_string()))
+}
+
+#[test]
+fn test_remove_backlink() -> Result<()> {
+    let dir = crate::test_util::TempDir::new();
+    let mut writer = LiveStorageWriter::new(&dir)?;
+    let target = RecordId::new(1, 1, 1);
+    let sources = (0..5).map(|i| RecordId::new(2, 2, i)).collect::<Vec<_>>();
+    for source in &sources {
+        writer.log_backlink(&target, source)?;
+    }
+
+    // head, middle, tail
+    for i in [0, 2, 4] {
+        assert!(writer.remove_backlink(&target, &sources[i])?);
+    }
+    assert!(!writer.remove_backlink(&target, &sources[0])?);
+
+    let mut reader = LiveStorageReader::new(&dir)?;
+    let mut backlinks = BTreeSet::new();
+    reader.read_backlinks(&target, &mut backlinks)?;
+    assert_eq!(backlinks, BTreeSet::from([sources[1], sources[3]]));
+
+    // a new reader finds it through hash.dat too
+    assert!(reader.hash.is_some());
+
+    // empty the chain entirely, then reuse it
+    writer.remove_backlink(&target, &sources[1])?;
+    writer.remove_backlink(&target, &sources[3])?;
+    assert!(writer.read_backlinks(&target)?.is_empty());
+    writer.log_backlink(&target, &sources[0])?;
+    let entries = writer.read_backlinks(&target)?;
+    assert_eq!(entries.len(), 1);
+    assert!(entries[0].source == sources[0]);
+
+    Ok(())
+}
+
+#[test]
+fn test_concurrent_writers() -> Result<()> {
+    let dir = crate::test_util::TempDir::new();
+    let mut first = LiveStorageWriter::new(&dir)?;
+    let mut second = LiveStorageWriter::new(&dir)?;
+    let source = RecordId::new(1, 1, 1);
+    let targets = (0..50_000)
+        .map(|i| RecordId::new(2, 2, i))
+        .collect::<Vec<_>>();
+
+    // both writers see each other's targets, even after one of them has grown the hash table
+    for (i, target) in targets.iter().enumerate() {
+        let (writer, other) = match i % 2 {
+            0 => (&mut first, &mut second),
+            _ => (&mut second, &mut first),
+        };
+        writer.log_backlink(target, &source)?;
+        other.log_backlink(target, &RecordId::new(1, 1, 2))?;
+    }
+
+    let mut reader = LiveStorageReader::new(&dir)?;
+    assert_eq!(count_index_entries(&reader.index)?, targets.len() as u64);
+    for target in targets.iter().step_by(997) {
+        let mut backlinks = BTreeSet::new();
+        reader.read_backlinks(target, &mut backlinks)?;
+        assert_eq!(backlinks, BTreeSet::from([source, RecordId::new(1, 1, 2)]));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_recover() -> Result<()> {
+    let dir = crate::test_util::TempDir::new();
+    let target = RecordId::new(1, 1, 1);
+    let sources = (0..3).map(|i| RecordId::new(2, 2, i)).collect::<Vec<_>>();
+
+    let mut writer = LiveStorageWriter::new(&dir)?;
+    writer.log_backlink(&target, &sources[0])?;
+    writer.log_backlink(&target, &sources[1])?;
+
+    // die after linking a new entry onto the tail, but before updating the index
+    writer.locked(|w| {
+        let index_value = w.find_in_index(&target)?.unwrap();
+        set_pending(&w.index_file, Some(index_value.idx))?;
+        let slot = w.alloc_entry_slot()?;
+        let mut entry = BacklinkEntry {
+            source: sources[2],
+            next: 0,
+            prev: (index_value.tail as i64 - slot as i64) as i32,
+        };
+        pwrite_all(
+            &w.links_file,
+            entry.as_mut_bytes(),
+            usize::try_from(slot).unwrap() * BACKLINK_ENTRY_SIZE,
+        )?;
+        let next = (slot - index_value.tail) as i32;
+        pwrite_all(
+            &w.links_file,
+            next.as_bytes(),
+            usize::try_from(index_value.tail).unwrap() * BACKLINK_ENTRY_SIZE + NEXT_OFFSET,
+        )?;
+        Ok(())
+    })?;
+    // ...and in the middle of appending an index entry
+    writer.index_file_append.write_all(&[0xff; 7])?;
+    drop(writer);
+    // pending write, torn index entry, and the chain running past the tail
+    assert_eq!(fsck(&dir, |_| {})?.len(), 3);
+
+    let mut writer = LiveStorageWriter::new(&dir)?;
+    let mut seen = 0;
+    assert!(fsck(&dir, |_| seen += 1)?.is_empty());
+    assert_eq!(seen, 4);
+    let header = read_index_header(&writer.index_file)?;
+    assert_eq!(header.pending, 0);
+    assert_eq!(count_index_entries(&writer.index_file)?, 1);
+    assert_eq!(
+        writer.index_file.metadata()?.len() as usize,
+        INDEX_HEADER_SIZE + INDEX_ENTRY_SIZE
+    );
+
+    // the tail was moved onto the new entry, so appending after it keeps the whole chain
+    writer.log_backlink(&target, &RecordId::new(2, 2, 3))?;
+    let mut reader = LiveStorageReader::new(&dir)?;
+    let mut backlinks = BTreeSet::new();
+    reader.read_backlinks(&target, &mut backlinks)?;
+    assert_eq!(backlinks.len(), 4);
+    assert!(writer.remove_backlink(&target, &RecordId::new(2, 2, 3))?);
+
+    Ok(())
+}
+
+#[test]
+fn test_recover_torn_links() -> Result<()> {
+    let dir = crate::test_util::TempDir::new();
+    let target = RecordId::new(1, 1, 1);
+    let links_len = |w: &LiveStorageWriter| -> Result<u64> { Ok(w.links_file.metadata()?.len()) };
+
+    let mut writer = LiveStorageWriter::new(&dir)?;
+    writer.log_backlink(&target, &RecordId::new(2, 2, 0))?;
+    let expected = links_len(&writer)?;
+
+    // die halfway through writing the next slot, before num_records was bumped
+    pwrite_all(
+        &writer.links_file,
+        &[0xff; BACKLINK_ENTRY_SIZE / 2],
+        expected as usize,
+    )?;
+    drop(writer);
+    assert_eq!(fsck(&dir, |_| {})?.len(), 1);
+
+    let mut writer = LiveStorageWriter::new(&dir)?;
+    writer.locked(|_| Ok(()))?;
+    assert_eq!(links_len(&writer)?, expected);
+
+    // and one that went missing after it was counted
+    writer.links_file.set_len(expected - 3)?;
+    writer.log_backlink(&target, &RecordId::new(2, 2, 1))?;
+    assert_eq!(links_len(&writer)?, expected + BACKLINK_ENTRY_SIZE as u64);
+    assert!(fsck(&dir, |_| {})?.is_empty());
+
+    let mut backlinks = BTreeSet::new();
+    LiveStorageReader::new(&dir)?.read_backlinks(&target, &mut backlinks)?;
+    assert_eq!(backlinks.len(), 2);
+
+    Ok(())
+}
+
+#[test]
+fn test_long_jumps() -> Result<()> {
+    let dir = crate::test_util::TempDir::new();
+    let target = RecordId::new(1, 1, 1);
+    let sources = (0..4).map(|i| RecordId::new(2, 2, i)).collect::<Vec<_>>();
+
+    let mut writer = LiveStorageWriter::new(&dir)?;
+    writer.log_backlink(&target, &sources[0])?;
+    writer.log_backlink(&target, &sources[1])?;
+
+    // pretend billions of backlinks to other targets were logged since (links.dat stays sparse)
+    writer.locked(|w| {
+        let mut header = read_index_header(&w.index_file)?;
+        header.num_records = 3 << 30;
+        pwrite_all(&w.index_file, header.as_mut_bytes(), 0)?;
+        w.links_file
+            .set_len(header.num_records * BACKLINK_ENTRY_SIZE as u64)?;
+        Ok(())
+    })?;
+    writer.log_backlink(&target, &sources[2])?;
+    writer.log_backlink(&target, &sources[3])?;
+    let header = read_index_header(&writer.index_file)?;
+    assert_ne!(header.format.flags & LIVE_FLAG_LONG_JUMPS, 0);
+
+    let entries = writer.read_backlinks(&target)?;
+    assert_eq!(entries.len(), 4);
+    assert!(entries.iter().zip(&sources).all(|(e, s)| &e.source == s));
+    let mut seen = 0;
+    assert!(fsck(&dir, |_| seen += 1)?.is_empty());
+    assert_eq!(seen, 5);
+
+    // the moved entry can't be unlinked, but it does get flagged
+    assert!(writer.remove_backlink(&target, &sources[1])?);
+    assert!(writer.remove_backlink(&target, &sources[2])?);
+    let mut reader = LiveStorageReader::new(&dir)?;
+    let mut backlinks = BTreeSet::new();
+    reader.read_backlinks(&target, &mut backlinks)?;
+    assert_eq!(backlinks, BTreeSet::from([sources[0], sources[3]]));
+    assert!(fsck(&dir, |_| {})?.is_empty());
+
+    // recovery walks across the jump too
+    let index_value = writer.find_in_index(&target)?.unwrap();
+    set_pending(&writer.index_file, Some(index_value.idx))?;
+    drop(writer);
+    drop(LiveStorageWriter::new(&dir)?);
+    assert!(fsck(&dir, |_| {})?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_cids() -> Result<()> {
+    let dir = crate::test_util::TempDir::new();
+    let target = RecordId::new(1, 1, 1);
+    let sources = (0..4).map(|i| RecordId::new(2, 2, i)).collect::<Vec<_>>();
+
+    // opened before there is a cids.dat
+    let mut writer = LiveStorageWriter::new(&dir)?;
+    let mut reader = LiveStorageReader::new(&dir)?;
+    writer.log_backlink(&target, &sources[0])?;
+    writer.log_backlink_with_cid(&target, &sources[1], 11)?;
+    assert!(!dir.join(REVERSE_DIR).join(CIDS_FILE).exists());
+
+    // the backlink that gets moved along by a jump keeps its cid
+    writer.locked(|w| {
+        let mut header = read_index_header(&w.index_file)?;
+        header.num_records = 3 << 30;
+        pwrite_all(&w.index_file, header.as_mut_bytes(), 0)?;
+        w.links_file
+            .set_len(header.num_records * BACKLINK_ENTRY_SIZE as u64)?;
+        Ok(())
+    })?;
+    writer.log_backlink_with_cid(&target, &sources[2], 12)?;
+    writer.log_backlink(&target, &sources[3])?;
+
+    let (mut backlinks, mut cids) = (BTreeSet::new(), BTreeMap::new());
+    reader.read_backlinks_with_cids(&target, &mut backlinks, &mut cids)?;
+    assert_eq!(backlinks.len(), 4);
+    assert_eq!(cids, BTreeMap::from([(sources[1], 11), (sources[2], 12)]));
+
+    Ok(())
+}
+
+#[test]
+fn test_identity_links() -> Result<()> {
+    let dir = crate::test_util::TempDir::new();
+    let (did, record) = (7, RecordId::new(7, 1, 1));
+    let source = RecordId::new(2, 2, 2);
+
+    let mut writer = LiveStorageWriter::new(&dir)?;
+    writer.log_backlink(&record, &source)?;
+    writer.log_identity_link(did, &source)?;
+
+    // identity links get chains of their own, so they don't show up as backlinks to records
+    let mut identity = LiveStorageReader::new(dir.join(IDENTITY_DIR))?;
+    let mut sources = BTreeSet::new();
+    identity.read_backlinks(&RecordId::identity(did), &mut sources)?;
+    assert_eq!(sources, BTreeSet::from([source]));
+    assert!(writer.read_backlinks(&RecordId::identity(did))?.is_empty());
+
+    assert_eq!(writer.delete_source(&source)?, 2);
+    let mut sources = BTreeSet::new();
+    identity.read_backlinks(&RecordId::identity(did), &mut sources)?;
+    assert!(sources.is_empty());
+
+    Ok(())
+}
@@ -1,17 +1,91 @@
 use std::{
     fs::File,
     ops::{Deref, DerefMut},
-    path::PathBuf,
+    path::Path,
 };
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use nix::fcntl::{Flock, FlockArg};
 
 use crate::AppContext;
 
 use super::live::{LiveStorageReader, LiveStorageWriter};
 
+// everyone using a live store holds a lease on it, as flocks on two files in its directory:
+//   - use.lock: shared by readers and writers. cleanup takes it exclusively before removing
+//     the store, so it can't pull it out from under anybody
+//   - write.lock: shared by writers. compaction takes it exclusively, so it never starts on
+//     (and nobody starts writing to) a store that is still being written to
+// the kernel drops the locks when a process dies, so there is nothing stale to clean up.
+const USE_LOCK: &str = "use.lock";
+const WRITE_LOCK: &str = "write.lock";
+
+fn try_lock(dir: &Path, file_name: &str, arg: FlockArg) -> Result<Option<Flock<File>>> {
+    let file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir.join(file_name))?;
+    match Flock::lock(file, arg) {
+        Ok(file) => Ok(Some(file)),
+        Err((_, nix::errno::Errno::EWOULDBLOCK)) => Ok(None),
+        Err((_, e)) => Err(e.into()),
+    }
+}
+
+pub struct StoreLease {
+    _use: Flock<File>,
+    _write: Option<Flock<File>>,
+}
+
+impl StoreLease {
+    /// for reading the store. fails if it is being cleaned up
+    pub fn reader(dir: &Path) -> Result<Self> {
+        let use_lock = try_lock(dir, USE_LOCK, FlockArg::LockSharedNonblock)?
+            .with_context(|| format!("{} is being removed", dir.display()))?;
+        Ok(Self {
+            _use: use_lock,
+            _write: None,
+        })
+    }
+
+    /// for writing to the store. fails if it is being compacted or cleaned up
+    pub fn writer(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let mut lease = Self::reader(dir)?;
+        let write_lock = try_lock(dir, WRITE_LOCK, FlockArg::LockSharedNonblock)?
+            .with_context(|| format!("{} is being compacted", dir.display()))?;
+        lease._write = Some(write_lock);
+        Ok(lease)
+    }
+
+    /// for compacting the store, once nobody is writing to it anymore
+    pub fn try_compact(dir: &Path) -> Result<Option<Self>> {
+        let Some(use_lock) = try_lock(dir, USE_LOCK, FlockArg::LockSharedNonblock)? else {
+            return Ok(None);
+        };
+        let Some(write_lock) = try_lock(dir, WRITE_LOCK, FlockArg::LockExclusiveNonblock)? else {
+            return Ok(None);
+        };
+        Ok(Some(Self {
+            _use: use_lock,
+            _write: Some(write_lock),
+        }))
+    }
+
+    /// for removing the store, once nobody is using it at all
+    pub fn try_remove(dir: &Path) -> Result<Option<Self>> {
+        Ok(
+            try_lock(dir, USE_LOCK, FlockArg::LockExclusiveNonblock)?.map(|use_lock| Self {
+                _use: use_lock,
+                _write: None,
+            }),
+        )
+    }
+}
+
 pub struct LiveWriteHandle {
-    pidfile: PathBuf,
+    _lease: StoreLease,
     pub store_id: u64,
     pub writer: LiveStorageWriter,
 }
@@ -72,26 +146,19 @@ impl LiveWriteHandle {
         };
 
         let storage_dir = app.data_dir.join("live").join(name);
+        let lease = StoreLease::writer(&storage_dir)?;
         let writer = LiveStorageWriter::new(&storage_dir)?;
-        let pidfile = storage_dir.join(format!("{}.pid", std::process::id()));
-        File::create_new(&pidfile)?;
 
         Ok(Self {
-            pidfile,
+            _lease: lease,
             store_id: id,
             writer,
         })
     }
 }
 
-impl Drop for LiveWriteHandle {
-    fn drop(&mut self) {
-        let _ = std::fs::remove_file(&self.pidfile);
-    }
-}
-
 pub struct LiveReadHandle {
-    pidfile: PathBuf,
+    _lease: StoreLease,
     pub reader: LiveStorageReader,
 }
 impl Deref for LiveReadHandle {
@@ -108,16 +175,39 @@ impl DerefMut for LiveReadHandle {
 
 impl LiveReadHandle {
     pub fn new(app: &AppContext, name: String) -> Result<Self> {
-        let storage_dir = app.data_dir.join("live").join(name);
+        Self::open(&app.data_dir, &name)
+    }
+
+    pub fn open(data_dir: &Path, name: &str) -> Result<Self> {
+        let storage_dir = data_dir.join("live").join(name);
+        let lease = StoreLease::reader(&storage_dir)?;
         let reader = LiveStorageReader::new(&storage_dir)?;
-        let pidfile = storage_dir.join(format!("{}.pid", std::process::id()));
-        File::create_new(&pidfile)?;
 
-        Ok(Self { pidfile, reader })
+        Ok(Self {
+            _lease: lease,
+            reader,
+        })
     }
 }
-impl Drop for LiveReadHandle {
-    fn drop(&mut self) {
-        let _ = std::fs::remove_file(&self.pidfile);
-    }
+
+#[test]
+fn test_store_leases() -> Result<()> {
+    let dir = crate::test_util::TempDir::new();
+
+    // readers don't get in the way of compaction, only of removal
+    let writer = StoreLease::writer(&dir)?;
+    let reader = StoreLease::reader(&dir)?;
+    assert!(StoreLease::try_compact(&dir)?.is_none());
+    drop(writer);
+    let compaction = StoreLease::try_compact(&dir)?.unwrap();
+    assert!(StoreLease::writer(&dir).is_err());
+    assert!(StoreLease::try_remove(&dir)?.is_none());
+    drop((reader, compaction));
+
+    let removal = StoreLease::try_remove(&dir)?.unwrap();
+    assert!(StoreLease::reader(&dir).is_err());
+    drop(removal);
+    assert!(StoreLease::reader(&dir).is_ok());
+
+    Ok(())
 }
@@ -0,0 +1,327 @@
+use std::{
+    fs::File,
+    mem::size_of,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
+
+use crate::data::{record::RecordId, Padding};
+
+use super::{
+    live::{count_index_entries, read_index_entries, read_index_entry, RecordIndexEntry},
+    pread_all, pwrite_all, FormatInfo,
+};
+
+// an open-addressing (linear probing) hash table from target to its position in index.dat,
+// kept in hash.dat next to it so that nobody has to load the whole index into memory.
+// the table is never resized in place: when it fills up we build a bigger one next to it
+// and rename it over the top, so readers holding the old file just see a frozen snapshot.
+
+#[derive(Debug, Clone, Copy, KnownLayout, Immutable, IntoBytes, FromBytes)]
+#[repr(C)]
+pub struct HashIndexHeader {
+    // number of buckets, always a power of two
+    pub capacity: u64,
+    // number of index.dat entries inserted so far (always a prefix of index.dat)
+    pub num_entries: u64,
+    pub format: FormatInfo,
+    _pad: Padding<32>,
+}
+const HEADER_SIZE: usize = size_of::<HashIndexHeader>();
+const _: [(); 64] = [(); HEADER_SIZE];
+
+// each bucket is a u64 of (fingerprint << IDX_BITS) | (idx + 1), or 0 when empty
+const BUCKET_SIZE: usize = size_of::<u64>();
+const IDX_BITS: u32 = 40;
+const IDX_MASK: u64 = (1 << IDX_BITS) - 1;
+
+const INITIAL_CAPACITY: u64 = 1 << 16;
+// grow past 70% full
+const MAX_LOAD_NUMERATOR: u64 = 7;
+const MAX_LOAD_DENOMINATOR: u64 = 10;
+
+pub const HASH_FILE: &str = "hash.dat";
+
+const HASH_MAGIC: [u8; 4] = *b"BSLH";
+// 0: from before headers had a format, otherwise the same as 1
+// 1: HashIndexHeader has a format
+const HASH_VERSION: u32 = 1;
+const HASH_FLAGS: u64 = 0;
+
+fn mix(mut x: u64) -> u64 {
+    // splitmix64 finalizer
+    x ^= x >> 30;
+    x = x.wrapping_mul(0xbf58476d1ce4e5b9);
+    x ^= x >> 27;
+    x = x.wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+pub fn hash_record_id(id: &RecordId) -> u64 {
+    mix(mix(mix(id.rkey) ^ id.did) ^ id.collection as u64)
+}
+
+fn inode(path: &Path) -> Option<u64> {
+    path.metadata().ok().map(|m| m.ino())
+}
+
+pub struct LiveHashIndex {
+    path: PathBuf,
+    file: File,
+    capacity: u64,
+    writable: bool,
+}
+
+impl LiveHashIndex {
+    /// opens an existing hash index without writing to it
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let path = dir.as_ref().join(HASH_FILE);
+        let file = File::options().read(true).open(&path)?;
+        let mut index = Self {
+            path,
+            file,
+            capacity: 0,
+            writable: false,
+        };
+        index.capacity = index.checked_header()?.capacity;
+        Ok(index)
+    }
+
+    /// opens (or creates) the hash index for writing, and catches it up with `index`
+    /// if it is missing entries (e.g. for stores that were written before hash.dat existed)
+    pub fn open_for_writing(dir: impl AsRef<Path>, index: &File) -> Result<Self> {
+        let path = dir.as_ref().join(HASH_FILE);
+        let count = count_index_entries(index)?;
+
+        let mut hash_index = match Self::open_writable(&path) {
+            Ok(hash_index) if hash_index.header()?.num_entries <= count => hash_index,
+            _ => {
+                Self::create(&path, INITIAL_CAPACITY)?;
+                Self::open_writable(&path)?
+            }
+        };
+
+        hash_index.catch_up(index)?;
+
+        Ok(hash_index)
+    }
+
+    /// inserts whatever is in index.dat but not in the table yet
+    /// (e.g. because another writer appended to the index and died before getting here)
+    pub fn catch_up(&mut self, index: &File) -> Result<()> {
+        let count = count_index_entries(index)?;
+        let mut idx = self.header()?.num_entries;
+        while idx < count {
+            for entry in read_index_entries(index, idx, count)? {
+                self.insert(index, &entry.target, idx)?;
+                idx += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn open_writable(path: &Path) -> Result<Self> {
+        let file = File::options().read(true).write(true).open(path)?;
+        let mut index = Self {
+            path: path.to_path_buf(),
+            file,
+            capacity: 0,
+            writable: true,
+        };
+        index.capacity = index.checked_header()?.capacity;
+        Ok(index)
+    }
+
+    fn create(path: &Path, capacity: u64) -> Result<()> {
+        let file = File::create(path)?;
+        file.set_len(HEADER_SIZE as u64 + capacity * BUCKET_SIZE as u64)?;
+        let header = HashIndexHeader {
+            capacity,
+            num_entries: 0,
+            format: FormatInfo::new(HASH_MAGIC, HASH_VERSION, HASH_FLAGS),
+            _pad: Default::default(),
+        };
+        pwrite_all(&file, header.as_bytes(), 0)?;
+        Ok(())
+    }
+
+    fn header(&self) -> Result<HashIndexHeader> {
+        let mut buf = [0u8; HEADER_SIZE];
+        pread_all(&self.file, &mut buf, 0)?;
+        Ok(zerocopy::transmute!(buf))
+    }
+
+    // the table is only derived from index.dat, so when in doubt it just gets rebuilt
+    fn checked_header(&self) -> Result<HashIndexHeader> {
+        let header = self.header()?;
+        header.format.check(HASH_MAGIC, HASH_VERSION, HASH_FLAGS)?;
+        Ok(header)
+    }
+
+    pub fn num_entries(&self) -> Result<u64> {
+        Ok(self.header()?.num_entries)
+    }
+
+    fn read_bucket(&self, bucket: u64) -> Result<u64> {
+        let mut buf = [0u8; BUCKET_SIZE];
+        pread_all(
+            &self.file,
+            &mut buf,
+            HEADER_SIZE + bucket as usize * BUCKET_SIZE,
+        )?;
+        Ok(u64::from_ne_bytes(buf))
+    }
+
+    fn write_bucket(&self, bucket: u64, value: u64) -> Result<()> {
+        pwrite_all(
+            &self.file,
+            value.as_bytes(),
+            HEADER_SIZE + bucket as usize * BUCKET_SIZE,
+        )
+    }
+
+    /// looks up `target`, returning its position in index.dat and its current entry
+    pub fn find(&self, index: &File, target: &RecordId) -> Result<Option<(u64, RecordIndexEntry)>> {
+        let hash = hash_record_id(target);
+        let fingerprint = hash >> IDX_BITS;
+        let mask = self.capacity - 1;
+
+        let mut bucket = hash & mask;
+        for _ in 0..self.capacity {
+            let value = self.read_bucket(bucket)?;
+            if value == 0 {
+                return Ok(None);
+            }
+            if value >> IDX_BITS == fingerprint {
+                let idx = (value & IDX_MASK) - 1;
+                let entry = read_index_entry(index, idx)?;
+                if &entry.target == target {
+                    return Ok(Some((idx, entry)));
+                }
+            }
+            bucket = (bucket + 1) & mask;
+        }
+
+        Ok(None)
+    }
+
+    /// records that `target` lives at `idx`, which must be the next entry of index.dat
+    pub fn insert(&mut self, index: &File, target: &RecordId, idx: u64) -> Result<()> {
+        let mut header = self.header()?;
+        debug_assert_eq!(
+            header.num_entries, idx,
+            "hash index inserts must be in order"
+        );
+        if (header.num_entries + 1) * MAX_LOAD_DENOMINATOR > self.capacity * MAX_LOAD_NUMERATOR {
+            self.grow(index, header.num_entries)?;
+            header = self.header()?;
+        }
+
+        self.insert_bucket(target, idx)?;
+        header.num_entries += 1;
+        pwrite_all(&self.file, header.as_bytes(), 0)?;
+        Ok(())
+    }
+
+    fn insert_bucket(&self, target: &RecordId, idx: u64) -> Result<()> {
+        let hash = hash_record_id(target);
+        let mask = self.capacity - 1;
+        let value = (hash >> IDX_BITS << IDX_BITS) | (idx + 1);
+
+        let mut bucket = hash & mask;
+        loop {
+            if self.read_bucket(bucket)? == 0 {
+                return self.write_bucket(bucket, value);
+            }
+            bucket = (bucket + 1) & mask;
+        }
+    }
+
+    fn grow(&mut self, index: &File, num_entries: u64) -> Result<()> {
+        let tmp_path = self.path.with_extension("dat.tmp");
+        Self::create(&tmp_path, self.capacity * 2)?;
+        let mut grown = Self::open_writable(&tmp_path)?;
+
+        let mut idx = 0;
+        while idx < num_entries {
+            for entry in read_index_entries(index, idx, num_entries)? {
+                grown.insert_bucket(&entry.target, idx)?;
+                idx += 1;
+            }
+        }
+        let mut header = grown.header()?;
+        header.num_entries = num_entries;
+        pwrite_all(&grown.file, header.as_bytes(), 0)?;
+        grown.file.sync_all()?;
+
+        std::fs::rename(&tmp_path, &self.path)?;
+        grown.path = self.path.clone();
+        *self = grown;
+        Ok(())
+    }
+
+    /// reopens the hash index if it has been replaced by a bigger one since we opened it.
+    /// returns whether anything changed
+    pub fn refresh(&mut self) -> Result<bool> {
+        let current = self.file.metadata()?.ino();
+        if inode(&self.path).is_none_or(|ino| ino == current) {
+            return Ok(false);
+        }
+
+        self.file = File::options()
+            .read(true)
+            .write(self.writable)
+            .open(&self.path)?;
+        self.capacity = self.checked_header()?.capacity;
+        Ok(true)
+    }
+}
+
+#[test]
+fn test_hash_index() -> Result<()> {
+    use super::live::{LiveStorageReader, LiveStorageWriter};
+    use std::collections::BTreeSet;
+
+    let dir = crate::test_util::TempDir::new();
+    let source = RecordId::new(1, 1, 1);
+    let targets = (0..50_000)
+        .map(|i| RecordId::new(i % 7, 2, i))
+        .collect::<Vec<_>>();
+
+    // enough targets to make the table grow at least once
+    let mut writer = LiveStorageWriter::new(&dir)?;
+    for target in &targets {
+        writer.log_backlink(target, &source)?;
+    }
+    drop(writer);
+    assert!(LiveHashIndex::open(&dir)?.capacity > INITIAL_CAPACITY);
+
+    // a missing hash.dat gets rebuilt from index.dat
+    std::fs::remove_file(dir.join(HASH_FILE))?;
+    drop(LiveStorageWriter::new(&dir)?);
+
+    let mut reader = LiveStorageReader::new(&dir)?;
+    for target in targets.iter().step_by(997) {
+        let mut backlinks = BTreeSet::new();
+        reader.read_backlinks(target, &mut backlinks)?;
+        assert_eq!(backlinks, BTreeSet::from([source]));
+    }
+    assert!(reader
+        .find_index_entry(&RecordId::new(0, 2, 50_000))?
+        .is_none());
+
+    // without hash.dat readers fall back to their own map, and pick up targets as they're added
+    std::fs::remove_file(dir.join(HASH_FILE))?;
+    let mut reader = LiveStorageReader::new(&dir)?;
+    assert!(reader.find_index_entry(&targets[123])?.is_some());
+    let new_target = RecordId::new(0, 2, 50_000);
+    assert!(reader.find_index_entry(&new_target)?.is_none());
+    let mut writer = LiveStorageWriter::new(&dir)?;
+    writer.log_backlink(&new_target, &source)?;
+    assert!(reader.find_index_entry(&new_target)?.is_some());
+
+    Ok(())
+}
@@ -0,0 +1,329 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, BinaryHeap},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+
+use crate::db::DbConnection;
+
+use super::{
+    compacted::{CompactedStorageReader, CompactedStorageWriter},
+    compaction::{is_registered, publish_dir, tmp_dir, CompactionLock},
+    deletion::{apply_pending_deletes, clear_pending_deletes_mark, mark_pending_deletes},
+    live::SUBSTORE_DIRS,
+};
+
+// size-tiered: once this many compacted stores fall into the same size tier, merge them
+const MERGE_FAN_IN: usize = 4;
+const MAX_MERGE_INPUTS: usize = 16;
+
+fn store_size(store_dir: &Path) -> u64 {
+    ["index.dat", "links.dat"]
+        .into_iter()
+        .flat_map(|file| {
+            std::iter::once(store_dir.to_path_buf())
+                .chain(SUBSTORE_DIRS.map(|sub_dir| store_dir.join(sub_dir)))
+                .map(move |dir| dir.join(file))
+        })
+        .map(|path| path.metadata().map(|m| m.len()).unwrap_or_default())
+        .sum()
+}
+
+/// picks the oldest compacted stores from the smallest size tier (powers of 4) that is full
+pub fn find_merge_candidates(data_dir: &Path, db: &DbConnection) -> Result<Option<Vec<String>>> {
+    let mut statement = db.prepare(
+        "SELECT name FROM data_stores WHERE type = 'compacted' AND compaction_in_progress = 0 ORDER BY id ASC",
+    )?;
+    let names = statement
+        .query_map((), |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut tiers = BTreeMap::<u32, Vec<String>>::new();
+    for name in names {
+        let size = store_size(&data_dir.join("compacted").join(&name));
+        tiers.entry(size.max(1).ilog(4)).or_default().push(name);
+    }
+
+    Ok(tiers
+        .into_values()
+        .find(|names| names.len() >= MERGE_FAN_IN)
+        .map(|mut names| {
+            names.truncate(MAX_MERGE_INPUTS);
+            names
+        }))
+}
+
+/// marks every store as being compacted, or none of them if any is unavailable
+pub fn claim_stores(
+    data_dir: &Path,
+    db: &mut DbConnection,
+    names: &[String],
+) -> Result<Vec<CompactionLock>> {
+    let mut locks = Vec::with_capacity(names.len());
+    for name in names {
+        match CompactionLock::try_acquire(data_dir, name)? {
+            Some(lock) => locks.push(lock),
+            None => anyhow::bail!("{name} is already being compacted"),
+        }
+    }
+
+    let tx = db.transaction()?;
+    for name in names {
+        let changed = tx.execute(
+            "UPDATE data_stores SET compaction_in_progress = 1 WHERE name = ? AND type = 'compacted' AND compaction_in_progress = 0",
+            [name],
+        )?;
+        if changed != 1 {
+            anyhow::bail!("{name} is not an idle compacted store");
+        }
+    }
+    tx.commit()?;
+    Ok(locks)
+}
+
+pub fn release_stores(db: &DbConnection, names: &[String]) -> Result<()> {
+    for name in names {
+        db.execute(
+            "UPDATE data_stores SET compaction_in_progress = 0 WHERE name = ?",
+            [name],
+        )?;
+    }
+    Ok(())
+}
+
+pub fn count_merge_input_entries(data_dir: &Path, names: &[String]) -> Result<u64> {
+    let mut total = 0;
+    for name in names {
+        let store_dir = data_dir.join("compacted").join(name);
+        total += CompactedStorageReader::new(&store_dir)?.num_entries()?;
+        for sub_dir in SUBSTORE_DIRS {
+            if store_dir.join(sub_dir).exists() {
+                total += CompactedStorageReader::new(store_dir.join(sub_dir))?.num_entries()?;
+            }
+        }
+    }
+    Ok(total)
+}
+
+// store names are timestamps, or ranges of them from earlier merges
+fn merged_store_name(names: &[String]) -> String {
+    let first = names
+        .first()
+        .and_then(|n| n.split('-').next())
+        .unwrap_or_default();
+    let last = names
+        .last()
+        .and_then(|n| n.rsplit('-').next())
+        .unwrap_or_default();
+    format!("{first}-{last}")
+}
+
+/// k-way merges the given (sorted) compacted stores into a new one at `output_dir`,
+/// dropping tombstoned and duplicate sources along the way.
+/// `on_entry` is called for every index entry that gets consumed from the inputs
+pub fn merge_compacted_stores(
+    input_dirs: &[PathBuf],
+    output_dir: &Path,
+    mut on_entry: impl FnMut(),
+) -> Result<()> {
+    let readers = input_dirs
+        .iter()
+        .map(CompactedStorageReader::new)
+        .collect::<Result<Vec<_>>>()?;
+    let mut cursors = readers
+        .iter()
+        .map(|r| r.index_entries())
+        .collect::<Result<Vec<_>>>()?;
+    // merging only ever drops sources, and a block's padding is smaller than its index entry
+    let max_links_size = input_dirs
+        .iter()
+        .flat_map(|dir| [dir.join("index.dat"), dir.join("links.dat")])
+        .map(|path| path.metadata().map(|m| m.len()).unwrap_or_default())
+        .sum();
+    let mut writer = CompactedStorageWriter::new_for_size(output_dir, max_links_size)?;
+
+    let mut heads = Vec::with_capacity(cursors.len());
+    let mut heap = BinaryHeap::new();
+    for (i, cursor) in cursors.iter_mut().enumerate() {
+        let head = cursor.next().transpose()?;
+        if let Some(entry) = head {
+            heap.push(Reverse((entry.target, i)));
+        }
+        heads.push(head);
+    }
+
+    while let Some(&Reverse((target, _))) = heap.peek() {
+        let (mut sources, mut cids) = (BTreeSet::new(), BTreeMap::new());
+        while let Some(&Reverse((next_target, i))) = heap.peek() {
+            if next_target != target {
+                break;
+            }
+            heap.pop();
+
+            let entry = heads[i].take().expect("heap and heads out of sync");
+            readers[i].read_backlinks_with_cids_from_index_entry(
+                &entry,
+                &mut sources,
+                &mut cids,
+            )?;
+            on_entry();
+
+            heads[i] = cursors[i].next().transpose()?;
+            if let Some(entry) = heads[i] {
+                heap.push(Reverse((entry.target, i)));
+            }
+        }
+
+        if !sources.is_empty() {
+            writer.log_backlinks_with_cids(&target, &sources, &cids)?;
+        }
+    }
+    writer.finish()?;
+
+    Ok(())
+}
+
+/// merges already-claimed compacted stores into a single new store,
+/// swaps them in `data_stores` and removes the old directories.
+/// the claim and its locks are released if anything goes wrong
+pub fn merge_stores(
+    data_dir: &Path,
+    db: &mut DbConnection,
+    names: &[String],
+    locks: Vec<CompactionLock>,
+    mut on_entry: impl FnMut(),
+) -> Result<String> {
+    let compacted_dir = data_dir.join("compacted");
+    let merged = merged_store_name(names);
+    let output_dir = compacted_dir.join(&merged);
+    let tmp_dir = tmp_dir(data_dir, &merged);
+    let input_dirs = names
+        .iter()
+        .map(|name| compacted_dir.join(name))
+        .collect::<Vec<_>>();
+
+    let Some(output_lock) = CompactionLock::try_acquire(data_dir, &merged)? else {
+        release_stores(db, names)?;
+        remove_locks(locks)?;
+        anyhow::bail!("{merged} is already being merged into");
+    };
+
+    let result = (|| {
+        if is_registered(db, &merged)? {
+            anyhow::bail!("can't merge into {merged}, there already is a store by that name");
+        }
+        if output_dir.exists() {
+            // recover_abandoned_compactions removes it, since it isn't registered
+            anyhow::bail!("can't merge into {merged}, a merge into it was never finished");
+        }
+        // left behind by a merge that never finished
+        if tmp_dir.exists() {
+            std::fs::remove_dir_all(&tmp_dir)?;
+        }
+
+        // deletes from here on might not make it into the output, so they're redone below
+        mark_pending_deletes(db, &merged)?;
+        merge_compacted_stores(&input_dirs, &tmp_dir, &mut on_entry)?;
+        for sub_dir in SUBSTORE_DIRS {
+            let sub_dirs = input_dirs
+                .iter()
+                .map(|dir| dir.join(sub_dir))
+                .filter(|dir| dir.exists())
+                .collect::<Vec<_>>();
+            if !sub_dirs.is_empty() {
+                merge_compacted_stores(&sub_dirs, &tmp_dir.join(sub_dir), &mut on_entry)?;
+            }
+        }
+        apply_pending_deletes(db, &merged, &tmp_dir)?;
+        publish_dir(&tmp_dir, &output_dir)?;
+
+        let tx = db.transaction()?;
+        tx.execute(
+            "INSERT INTO data_stores (name, type) VALUES (?, 'compacted')",
+            [&merged],
+        )?;
+        for name in names {
+            tx.execute("DELETE FROM data_stores WHERE name = ?", [name])?;
+        }
+        tx.commit()?;
+        Ok(())
+    })();
+    if let Err(e) = result {
+        // an output that did get published isn't registered, so recovery cleans it up
+        if tmp_dir.exists() {
+            let _ = std::fs::remove_dir_all(&tmp_dir);
+        }
+        let _ = clear_pending_deletes_mark(db, &merged);
+        let _ = release_stores(db, names);
+        let _ = output_lock.remove();
+        let _ = remove_locks(locks);
+        return Err(e);
+    }
+
+    // deletes that still went to the inputs while the output was being published.
+    // if this fails, the mark stays behind for recover_abandoned_compactions to finish up
+    apply_pending_deletes(db, &merged, &output_dir)?;
+    clear_pending_deletes_mark(db, &merged)?;
+    output_lock.remove()?;
+
+    // NB: readers that opened one of these stores before the swap keep working
+    // until they close their files. if we die before they're gone, they aren't
+    // registered anymore, so recover_abandoned_compactions removes them
+    for dir in input_dirs {
+        std::fs::remove_dir_all(dir)?;
+    }
+    remove_locks(locks)?;
+
+    Ok(merged)
+}
+
+fn remove_locks(locks: Vec<CompactionLock>) -> Result<()> {
+    for lock in locks {
+        lock.remove()?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_merge() -> Result<()> {
+    use crate::data::record::RecordId;
+
+    let dir = crate::test_util::TempDir::new();
+    let (a, b, c) = (
+        RecordId::new(1, 1, 1),
+        RecordId::new(2, 1, 1),
+        RecordId::new(3, 1, 1),
+    );
+    let (x, y, z) = (
+        RecordId::new(7, 2, 1),
+        RecordId::new(8, 2, 1),
+        RecordId::new(9, 2, 1),
+    );
+
+    let mut first = CompactedStorageWriter::new(dir.join("first"))?;
+    first.log_backlinks(&a, &BTreeSet::from([x, y]))?;
+    first.log_backlinks(&c, &BTreeSet::from([z]))?;
+    let mut second = CompactedStorageWriter::new(dir.join("second"))?;
+    second.log_backlinks(&a, &BTreeSet::from([y, z]))?;
+    second.log_backlinks(&b, &BTreeSet::from([x]))?;
+    super::compacted::CompactedStorageMutator::new(dir.join("first"))?.mark_deleted(&c, &z)?;
+
+    merge_compacted_stores(
+        &[dir.join("first"), dir.join("second")],
+        &dir.join("merged"),
+        || {},
+    )?;
+
+    let merged = CompactedStorageReader::new(dir.join("merged"))?;
+    assert_eq!(merged.num_entries()?, 2);
+    let mut sources = BTreeSet::new();
+    merged.read_backlinks(&a, &mut sources)?;
+    assert_eq!(sources, BTreeSet::from([x, y, z]));
+    sources.clear();
+    merged.read_backlinks(&b, &mut sources)?;
+    assert_eq!(sources, BTreeSet::from([x]));
+
+    Ok(())
+}
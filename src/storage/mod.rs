@@ -2,10 +2,18 @@ use std::os::fd::AsFd;
 
 use anyhow::Result;
 use nix::{libc::off_t, sys::uio};
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
 
+pub mod catalog;
 pub mod compacted;
+pub mod compacted_bloom;
+pub mod compaction;
+pub mod deletion;
 pub mod live;
 pub mod live_guards;
+pub mod live_hash;
+pub mod merge;
+pub mod store;
 
 pub fn pread_all(fd: impl AsFd, buf: &mut [u8], offset: usize) -> Result<()> {
     let mut read = 0;
@@ -26,3 +34,53 @@ pub fn pwrite_all(fd: impl AsFd, buf: &[u8], offset: usize) -> Result<()> {
     }
     Ok(())
 }
+
+/// identifies the format of a store file. every header carries one of these after its
+/// counts, in what used to be padding, so headers written before it existed are all zeroes
+/// here and get read as version 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq, KnownLayout, Immutable, IntoBytes, FromBytes)]
+#[repr(C)]
+pub struct FormatInfo {
+    pub magic: [u8; 4],
+    pub version: u32,
+    // features that change how the file has to be read. unknown ones mean we can't read it
+    pub flags: u64,
+}
+
+impl FormatInfo {
+    pub fn new(magic: [u8; 4], version: u32, flags: u64) -> Self {
+        Self {
+            magic,
+            version,
+            flags,
+        }
+    }
+
+    pub fn is_unversioned(&self) -> bool {
+        self.magic == [0; 4] && self.version == 0 && self.flags == 0
+    }
+
+    /// makes sure that we know how to read this file, returning the version to read it as
+    pub fn check(&self, magic: [u8; 4], max_version: u32, known_flags: u64) -> Result<u32> {
+        if self.is_unversioned() {
+            return Ok(0);
+        }
+        if self.magic != magic {
+            anyhow::bail!(
+                "expected a {:?} file but found {:?}",
+                String::from_utf8_lossy(&magic),
+                String::from_utf8_lossy(&self.magic)
+            );
+        }
+        if self.version > max_version {
+            anyhow::bail!(
+                "format version {} is newer than the newest one we can read ({max_version})",
+                self.version
+            );
+        }
+        if self.flags & !known_flags != 0 {
+            anyhow::bail!("unknown format flags {:#x}", self.flags & !known_flags);
+        }
+        Ok(self.version)
+    }
+}
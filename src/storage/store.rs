@@ -0,0 +1,290 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+
+use crate::data::record::RecordId;
+
+use super::{
+    compacted::CompactedStorageReader,
+    live::{count_index_entries, read_index_entries, LiveStorageReader, LiveStorageWriter},
+    live_guards::LiveReadHandle,
+};
+
+/// the operations every kind of data store supports,
+/// so that callers don't have to care which kind they are talking to
+pub trait BacklinkStore {
+    /// adds every source linking to `target` that hasn't been deleted to `sources`
+    fn read_backlinks(&mut self, target: &RecordId, sources: &mut BTreeSet<RecordId>)
+        -> Result<()>;
+
+    /// how many sources link to `target`, not counting deleted ones
+    fn count_backlinks(&mut self, target: &RecordId) -> Result<usize> {
+        let mut sources = BTreeSet::new();
+        self.read_backlinks(target, &mut sources)?;
+        Ok(sources.len())
+    }
+
+    /// calls `f` once for every target in the store (including ones whose sources have all
+    /// been deleted since)
+    fn for_each_target(&mut self, f: &mut dyn FnMut(&RecordId) -> Result<()>) -> Result<()>;
+
+    /// stores are read-only unless they say otherwise
+    fn log_backlink(&mut self, _target: &RecordId, _source: &RecordId) -> Result<()> {
+        anyhow::bail!("this store can't be written to")
+    }
+}
+
+fn for_each_live_target(
+    index: &std::fs::File,
+    f: &mut dyn FnMut(&RecordId) -> Result<()>,
+) -> Result<()> {
+    let count = count_index_entries(index)?;
+    let mut idx = 0;
+    while idx < count {
+        for entry in read_index_entries(index, idx, count)? {
+            f(&entry.target)?;
+            idx += 1;
+        }
+    }
+    Ok(())
+}
+
+impl BacklinkStore for LiveStorageReader {
+    fn read_backlinks(
+        &mut self,
+        target: &RecordId,
+        sources: &mut BTreeSet<RecordId>,
+    ) -> Result<()> {
+        LiveStorageReader::read_backlinks(self, target, sources)
+    }
+
+    fn for_each_target(&mut self, f: &mut dyn FnMut(&RecordId) -> Result<()>) -> Result<()> {
+        for_each_live_target(self.index(), f)
+    }
+}
+
+impl BacklinkStore for LiveReadHandle {
+    fn read_backlinks(
+        &mut self,
+        target: &RecordId,
+        sources: &mut BTreeSet<RecordId>,
+    ) -> Result<()> {
+        BacklinkStore::read_backlinks(&mut self.reader, target, sources)
+    }
+
+    fn for_each_target(&mut self, f: &mut dyn FnMut(&RecordId) -> Result<()>) -> Result<()> {
+        self.reader.for_each_target(f)
+    }
+}
+
+impl BacklinkStore for LiveStorageWriter {
+    fn read_backlinks(
+        &mut self,
+        target: &RecordId,
+        sources: &mut BTreeSet<RecordId>,
+    ) -> Result<()> {
+        let entries = LiveStorageWriter::read_backlinks(self, target)?;
+        sources.extend(
+            entries
+                .into_iter()
+                .map(|entry| entry.source)
+                .filter(|source| !source.is_deleted()),
+        );
+        Ok(())
+    }
+
+    fn for_each_target(&mut self, f: &mut dyn FnMut(&RecordId) -> Result<()>) -> Result<()> {
+        for_each_live_target(self.index(), f)
+    }
+
+    fn log_backlink(&mut self, target: &RecordId, source: &RecordId) -> Result<()> {
+        LiveStorageWriter::log_backlink(self, target, source)
+    }
+}
+
+impl BacklinkStore for CompactedStorageReader {
+    fn read_backlinks(
+        &mut self,
+        target: &RecordId,
+        sources: &mut BTreeSet<RecordId>,
+    ) -> Result<()> {
+        CompactedStorageReader::read_backlinks(self, target, sources)
+    }
+
+    fn for_each_target(&mut self, f: &mut dyn FnMut(&RecordId) -> Result<()>) -> Result<()> {
+        for entry in self.index_entries()? {
+            f(&entry?.target)?;
+        }
+        Ok(())
+    }
+}
+
+/// a store that only lives in memory, for tests
+#[derive(Default)]
+pub struct MemoryStore {
+    backlinks: BTreeMap<RecordId, BTreeSet<RecordId>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn remove_backlink(&mut self, target: &RecordId, source: &RecordId) -> bool {
+        self.backlinks
+            .get_mut(target)
+            .is_some_and(|sources| sources.remove(source))
+    }
+}
+
+impl BacklinkStore for MemoryStore {
+    fn read_backlinks(
+        &mut self,
+        target: &RecordId,
+        sources: &mut BTreeSet<RecordId>,
+    ) -> Result<()> {
+        if let Some(stored) = self.backlinks.get(target) {
+            sources.extend(stored.iter().copied());
+        }
+        Ok(())
+    }
+
+    fn for_each_target(&mut self, f: &mut dyn FnMut(&RecordId) -> Result<()>) -> Result<()> {
+        for target in self.backlinks.keys() {
+            f(target)?;
+        }
+        Ok(())
+    }
+
+    fn log_backlink(&mut self, target: &RecordId, source: &RecordId) -> Result<()> {
+        self.backlinks.entry(*target).or_default().insert(*source);
+        Ok(())
+    }
+}
+
+/// the kinds of store that the `type` column of `data_stores` can name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreType {
+    Live,
+    Compacted,
+}
+
+impl StoreType {
+    pub fn parse(store_type: &str) -> Result<Self> {
+        match store_type {
+            "live" => Ok(Self::Live),
+            "compacted" => Ok(Self::Compacted),
+            _ => anyhow::bail!("unknown store type {store_type}"),
+        }
+    }
+
+    /// where the store called `name` lives
+    pub fn dir(self, data_dir: &Path, name: &str) -> PathBuf {
+        match self {
+            Self::Live => data_dir.join("live").join(name),
+            Self::Compacted => data_dir.join("compacted").join(name),
+        }
+    }
+
+    /// opens the store (or substore) at `dir` for reading
+    pub fn open_reader(self, dir: &Path) -> Result<Box<dyn BacklinkStore>> {
+        Ok(match self {
+            Self::Live => Box::new(LiveStorageReader::new(dir)?),
+            Self::Compacted => Box::new(CompactedStorageReader::new(dir)?),
+        })
+    }
+}
+
+/// every data store at once, oldest first
+pub struct StoreSet {
+    stores: Vec<Box<dyn BacklinkStore>>,
+}
+
+impl StoreSet {
+    pub fn new(stores: Vec<Box<dyn BacklinkStore>>) -> Self {
+        Self { stores }
+    }
+
+    pub fn len(&self) -> usize {
+        self.stores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stores.is_empty()
+    }
+
+    pub fn read_backlinks(
+        &mut self,
+        target: &RecordId,
+        sources: &mut BTreeSet<RecordId>,
+    ) -> Result<()> {
+        for store in &mut self.stores {
+            store.read_backlinks(target, sources)?;
+        }
+        Ok(())
+    }
+
+    /// the same backlink can be in more than one store, so this has to read them all
+    pub fn count_backlinks(&mut self, target: &RecordId) -> Result<usize> {
+        let mut sources = BTreeSet::new();
+        self.read_backlinks(target, &mut sources)?;
+        Ok(sources.len())
+    }
+
+    /// calls `f` once for every target in any of the stores
+    pub fn for_each_target(&mut self, f: &mut dyn FnMut(&RecordId) -> Result<()>) -> Result<()> {
+        let mut seen = BTreeSet::new();
+        for store in &mut self.stores {
+            store.for_each_target(&mut |target| match seen.insert(*target) {
+                true => f(target),
+                false => Ok(()),
+            })?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_store_set() -> Result<()> {
+    use super::compacted::CompactedStorageWriter;
+
+    let dir = crate::test_util::TempDir::new();
+    let (a, b) = (RecordId::new(1, 1, 1), RecordId::new(1, 1, 2));
+    let sources = (0..4).map(|i| RecordId::new(2, 2, i)).collect::<Vec<_>>();
+
+    let mut compacted = CompactedStorageWriter::new(dir.join("compacted"))?;
+    compacted.log_backlinks(&a, &BTreeSet::from([sources[0], sources[1]]))?;
+    compacted.finish()?;
+    let mut live = LiveStorageWriter::new(dir.join("live"))?;
+    BacklinkStore::log_backlink(&mut live, &a, &sources[1])?;
+    BacklinkStore::log_backlink(&mut live, &b, &sources[2])?;
+    live.remove_backlink(&b, &sources[2])?;
+    let mut memory = MemoryStore::new();
+    memory.log_backlink(&b, &sources[3])?;
+
+    let mut compacted_reader = CompactedStorageReader::new(dir.join("compacted"))?;
+    assert!(compacted_reader.log_backlink(&b, &sources[3]).is_err());
+    let mut stores = StoreSet::new(vec![
+        Box::new(compacted_reader),
+        Box::new(LiveStorageReader::new(dir.join("live"))?),
+        Box::new(live),
+        Box::new(memory),
+    ]);
+
+    let mut found = BTreeSet::new();
+    stores.read_backlinks(&a, &mut found)?;
+    assert_eq!(found, BTreeSet::from([sources[0], sources[1]]));
+    assert_eq!(stores.count_backlinks(&a)?, 2);
+    assert_eq!(stores.count_backlinks(&b)?, 1);
+    let mut targets = Vec::new();
+    stores.for_each_target(&mut |target| {
+        targets.push(*target);
+        Ok(())
+    })?;
+    assert_eq!(targets, [a, b]);
+
+    Ok(())
+}
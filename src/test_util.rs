@@ -0,0 +1,49 @@
+use std::{
+    collections::BTreeMap,
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+use ipld_core::ipld::Ipld;
+
+/// a fresh directory under the system temp dir, removed again when it goes out of scope
+/// (even if the test fails)
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        Self(std::env::temp_dir().join(format!("backshots-{}", uuid::Uuid::new_v4())))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+/// an Ipld map, for building records
+pub fn map(entries: Vec<(&str, Ipld)>) -> Ipld {
+    Ipld::Map(BTreeMap::from_iter(
+        entries.into_iter().map(|(k, v)| (k.to_string(), v)),
+    ))
+}
+
+/// an at-uri string pointing at a record of `did:plc:a`
+pub fn uri(collection: &str, rkey: &str) -> Ipld {
+    Ipld::String(format!("at://did:plc:a/{collection}/{rkey}"))
+}